@@ -1,38 +1,550 @@
-use std::collections::VecDeque;
-
-use crate::components::{self, Point, Rect, Size, Image, Context, Pipelines};
-
-// TODO: Use renderBundle in conjunction with buffers to draw different lines in the canvas without reencoding the render pass.
+use bytemuck::{Pod, Zeroable};
+use std::collections::{HashMap, VecDeque};
+
+use crate::components::{self, Component, Point, PointF, Rect, Size, Image, Preview, ViewTransform, Context, Pipelines};
+use crate::components::history::{History, Snapshot};
+use crate::components::layer::Layer;
+use crate::components::project::{self, BrushSettings, Project, ProjectLayer};
+use crate::components::selection::{self, Selection};
+pub use crate::components::selection::SelectionKind;
+use crate::components::shape::{self, Shape};
+pub use crate::components::shape::ShapeKind;
+pub use crate::components::project::PROJECT_EXTENSION;
+use crate::components::fill;
+pub use crate::components::fill::FillMode;
+use crate::components::openraster;
+use crate::components::stabilizer::{Sample, Stabilizer};
+use crate::components::stroke_log::{LogEntry, LoggedStroke, StrokeLog};
+pub use crate::components::openraster::ORA_EXTENSION;
 
 const BACKGROUND_COLOR: [f32; 3] = [0., 0., 0.];
-const BRUSH_RADIUS: u32 = 3;
+const BRUSH_RADIUS: f32 = 3.;
+const MIN_BRUSH_RADIUS: f32 = 1.;
+const MAX_BRUSH_RADIUS: f32 = 100.;
+const BRUSH_COLOR: [f32; 3] = [1., 1., 1.];
+const BRUSH_OPACITY: f32 = 1.;
 const TEX_SIZE: Size = Size { w: 2000, h: 2000 };
 
-const POINTS_PER_BUFF: usize = 100;
-const BUFF_SIZE: wgpu::BufferSize = match wgpu::BufferSize::new((POINTS_PER_BUFF * std::mem::size_of::<Point>()) as u64) {
-	None => panic!("Error on BUFF_SIZE const definition"),
-	Some(x) => x,
-};
+const ZOOM_STEP: f32 = 1.1;
+const MIN_ZOOM: f32 = 0.1;
+const MAX_ZOOM: f32 = 32.;
+
+/// Elements the line buffers have room for at first, they grow when more are needed in a frame.
+const INITIAL_LINE_CAPACITY: usize = 128;
+/// Side of the square areas strokes are drawn in, matches the workgroup size of `draw_line`.
+const TILE_SIZE: i32 = 8;
+
+/// Maximum difference of each channel, out of 255, for a pixel to be filled.
+const FILL_TOLERANCE: u8 = 32;
+
+/// Length of the dashes of the selection outline, in framebuffer pixels.
+const ANTS_DASH: f32 = 4.;
+/// Framebuffer pixels per second the dashes move along the outline
+const ANTS_SPEED: f32 = 8.;
+
+/// Stroke point as laid out in the line buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct LinePoint {
+	/// Texture coordinates, texel `t` spans `t..t+1`
+	pub pos: PointF,
+	pub radius: f32,
+	pub opacity: f32,
+}
+
+/// Area of the canvas and the segments that may touch it, as laid out in the tile buffer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct Tile {
+	origin: Point,
+	segment_start: u32,
+	segment_count: u32,
+}
+
+/// Part of the line buffers used by a stroke in a frame.
+struct StrokeBatch {
+	/// Covered area, `None` if outside the canvas
+	area: Option<Rect>,
+	points: usize,
+	tiles: std::ops::Range<u32>,
+}
+
+/// Storage buffer that is reallocated with a power of two size when it's too small.
+struct GrowableBuffer {
+	label: &'static str,
+	buffer: wgpu::Buffer,
+	capacity: u64,
+}
+
+impl GrowableBuffer {
+	fn new(ctx: &Context, label: &'static str, capacity: u64) -> Self {
+		GrowableBuffer {
+			label,
+			buffer: Self::create(ctx, label, capacity),
+			capacity,
+		}
+	}
+
+	fn create(ctx: &Context, label: &str, size: u64) -> wgpu::Buffer {
+		ctx.device.create_buffer(&wgpu::BufferDescriptor {
+			label: Some(label),
+			size,
+			usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+			mapped_at_creation: false,
+		})
+	}
+
+	/// Makes room for `size` bytes. Returns whether the buffer was reallocated, bindings to it must be recreated.
+	fn reserve(&mut self, ctx: &Context, size: u64) -> bool {
+		if size <= self.capacity {
+			return false;
+		}
+		self.capacity = size.next_power_of_two();
+		self.buffer = Self::create(ctx, self.label, self.capacity);
+		true
+	}
+
+	/// Writes `data` at the start of the buffer, which must have room for it.
+	fn write(&self, encoder: &mut wgpu::CommandEncoder, ctx: &mut Context, data: &[u8]) {
+		if let Some(size) = wgpu::BufferSize::new(data.len() as u64) {
+			ctx.staging_belt.write_buffer(encoder, &self.buffer, 0, size, &ctx.device).copy_from_slice(data);
+		}
+	}
+}
+
+/// Maps normalized pen pressure to brush radius and opacity factors.
+#[derive(Copy, Clone, Debug)]
+pub struct PressureCurve {
+	/// Exponent applied to the pressure, values above 1 make light presses lighter.
+	pub gamma: f32,
+	/// Radius factor at zero pressure.
+	pub min_radius: f32,
+	/// Opacity at zero pressure.
+	pub min_opacity: f32,
+}
+
+impl Default for PressureCurve {
+	fn default() -> Self {
+		PressureCurve {
+			gamma: 1.5,
+			min_radius: 0.2,
+			min_opacity: 0.3,
+		}
+	}
+}
+
+impl PressureCurve {
+	/// Returns `(radius factor, opacity)` for a pressure in `0..=1`, full pressure always maps to `(1, 1)`.
+	pub fn map(&self, pressure: f32) -> (f32, f32) {
+		let p = pressure.clamp(0., 1.).powf(self.gamma);
+		(
+			self.min_radius + (1. - self.min_radius) * p,
+			self.min_opacity + (1. - self.min_opacity) * p,
+		)
+	}
+}
+
+/// How the brush color is combined with what is already painted. Values match `blend` in `canvas.wgsl`.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+	Normal = 0,
+	Multiply = 1,
+	Screen = 2,
+	Overlay = 3,
+	Darken = 4,
+	Lighten = 5,
+}
+
+impl BlendMode {
+	/// Cycles through all modes.
+	pub fn next(self) -> BlendMode {
+		use BlendMode::*;
+		match self {
+			Normal => Multiply,
+			Multiply => Screen,
+			Screen => Overlay,
+			Overlay => Darken,
+			Darken => Lighten,
+			Lighten => Normal,
+		}
+	}
+}
+
+impl TryFrom<u32> for BlendMode {
+	type Error = u32;
+
+	fn try_from(value: u32) -> Result<Self, Self::Error> {
+		use BlendMode::*;
+		[Normal, Multiply, Screen, Overlay, Darken, Lighten]
+			.into_iter()
+			.find(|m| *m as u32 == value)
+			.ok_or(value)
+	}
+}
+
+struct Stroke {
+	/// Points not drawn yet
+	points: VecDeque<LinePoint>,
+	/// Every point of the stroke, for the stroke log
+	recorded: Vec<LinePoint>,
+	/// Id of the layer the stroke is drawn on
+	layer: u32,
+	color: [f32; 3],
+	opacity: f32,
+	blend_mode: BlendMode,
+	/// Blended in linear light, fixed when the stroke starts
+	linear: bool,
+	erase: bool,
+}
+
+/// Push constants of the composite pipeline.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct LayerInput {
+	opacity: f32,
+	blend_mode: u32,
+	linear: u32,
+}
+
+/// Where the existing content ends up when the canvas is resized, as the fraction of the size
+/// difference placed before it: `(0, 0)` keeps it at the top-left corner, `(0.5, 0.5)` centers it.
+#[derive(Copy, Clone, Debug)]
+pub struct Anchor {
+	pub x: f32,
+	pub y: f32,
+}
+
+impl Anchor {
+	pub const TOP_LEFT: Anchor = Anchor { x: 0., y: 0. };
+
+	fn offset(&self, old: Size, new: Size) -> Point {
+		Point {
+			x: ((new.w as f32 - old.w as f32) * self.x).round() as i32,
+			y: ((new.h as f32 - old.h as f32) * self.y).round() as i32,
+		}
+	}
+}
+
+enum HistoryOp {
+	Undo,
+	Redo,
+}
 
 pub struct Canvas {
 	pipelines: std::sync::Arc<Pipelines>,
+	/// Presents the composited layers, its texture is the composite target.
 	image: Box<Image>,
+	/// Draws the shape being edited over the image
+	preview: Box<Preview>,
+	/// Draws the selection outline over the image
+	ants: Box<Preview>,
+	/// Start of the outline animation
+	ants_epoch: std::time::Instant,
+	/// Second composite target, layers are composited alternating between it and the image texture.
+	composite_scratch: wgpu::Texture,
+	/// Bottom to top
+	layers: Vec<Layer>,
+	/// Index of the layer new strokes are drawn on
+	active: usize,
+	next_layer_id: u32,
+	/// Copy of a layer as it was on the last commit, used to build undo snapshots
+	/// and as the destination strokes are composited onto.
+	base: wgpu::Texture,
+	/// Id of the layer `base` currently mirrors
+	base_layer: Option<u32>,
+	/// Coverage of the stroke being drawn, so overlapping segments are only composited once.
+	mask: wgpu::Texture,
 	tex_size: Size,
-	brush_radius: u32,
+	brush_radius: f32,
+	brush_color: [f32; 3],
+	brush_opacity: f32,
+	blend_mode: BlendMode,
+	/// Blend in linear light instead of directly on the sRGB encoded values.
+	linear_blending: bool,
+	eraser: bool,
+	antialias: bool,
+	fill_tolerance: u8,
+	/// Shape drawn on press instead of a stroke, freehand if `None`
+	shape_kind: Option<ShapeKind>,
+	fill_shapes: bool,
+	/// Shape being edited
+	shape: Option<Shape>,
+	/// Set when the shape being edited should be drawn on the next render
+	shape_done: bool,
+	/// Snaps shapes to 45° lines, squares and circles
+	constrain: bool,
+	/// Press and drag select instead of drawing, or move the selection when pressed inside it
+	select_kind: Option<SelectionKind>,
+	selection: Option<Selection>,
+	/// Selection being made, the press position followed by the lasso path
+	selecting: Option<Vec<PointF>>,
+	/// Press position of a selection being moved
+	moving: Option<PointF>,
+	/// Offset the selection is moved by on the next render
+	move_done: Option<Point>,
+	/// Moving leaves a copy of the selected pixels in place
+	duplicate: bool,
+	/// Selected fraction of each pixel in the red channel, strokes are restricted to it
+	selection_tex: wgpu::Texture,
+	/// Set when the selection texture doesn't match `selection`
+	selection_changed: bool,
 	backgroud: [f32; 3],
 
-	line_buff: wgpu::Buffer,
+	/// Points, tiles and per tile segments of the strokes drawn in a frame
+	line_buff: GrowableBuffer,
+	tile_buff: GrowableBuffer,
+	segment_buff: GrowableBuffer,
 	line_binding: wgpu::BindGroup,
-
-	line_points: VecDeque<VecDeque<Point>>,
-	mouse_pos: Option<Point>,
+	/// Stroke bind groups by layer id, dropped when the layer, base or mask textures are reallocated
+	stroke_bindings: HashMap<u32, wgpu::BindGroup>,
+	/// A composite pass per visible layer, recorded again when the layer stack or its settings change
+	composite_bundles: Option<Vec<wgpu::RenderBundle>>,
+
+	line_points: VecDeque<Stroke>,
+	view: ViewTransform,
+	/// Last cursor position relative to the viewport
+	cursor: Option<PointF>,
+	panning: bool,
+	/// Last cursor position in texture coordinates
+	mouse_pos: Option<PointF>,
+	pressure: f32,
+	pressure_curve: PressureCurve,
+	stabilizer: Stabilizer,
 	mouse_down: bool,
 	clear: bool,
+	/// Content waiting to replace a layer
+	upload: Option<(u32, Size, wgpu::Texture)>,
+	/// Filled area waiting to be copied to a layer
+	fill: Option<(u32, Rect, wgpu::Texture)>,
+
+	history: History,
+	history_ops: VecDeque<HistoryOp>,
+	stroke_log: StrokeLog,
+	/// Area modified since the last commit.
+	dirty: Option<Rect>,
+	/// Area of the layers changed since the last composite
+	damage: Option<Rect>,
+	/// View of the last render
+	presented: Option<ViewTransform>,
+}
+
+fn create_line_binding(ctx: &Context, pipelines: &Pipelines, buffers: [&GrowableBuffer; 3]) -> wgpu::BindGroup {
+	let entries = [0, 1, 2].map(|i| wgpu::BindGroupEntry {
+		binding: i,
+		resource: buffers[i as usize].buffer.as_entire_binding(),
+	});
+
+	ctx.device.create_bind_group(
+		&wgpu::BindGroupDescriptor {
+			label: Some("Canvas(Binding group 1)"),
+			layout: &pipelines.compute[2].get_bind_group_layout(1),
+			entries: &entries,
+		}
+	)
+}
+
+/// Adds the tiles touched by the segments of a stroke, `first` is the index of its first point in the line buffer.
+/// Returns the area covered by the stroke, clipped to the canvas.
+fn bin_segments(points: &[LinePoint], first: u32, canvas: Size, tiles: &mut Vec<Tile>, segments: &mut Vec<u32>) -> Option<Rect> {
+	let full = Rect { pos: Point { x: 0, y: 0 }, size: canvas };
+	let mut bins: HashMap<(i32, i32), Vec<u32>> = HashMap::new();
+	let mut area: Option<Rect> = None;
+
+	for (i, pair) in points.windows(2).enumerate() {
+		let (a, b) = (pair[0].pos, pair[1].pos);
+		// Antialiasing reaches half a pixel beyond the radius
+		let r = pair[0].radius.max(pair[1].radius) + 1.;
+
+		let min = Point { x: (a.x.min(b.x) - r).floor() as i32, y: (a.y.min(b.y) - r).floor() as i32 };
+		let max = Point { x: (a.x.max(b.x) + r).ceil() as i32, y: (a.y.max(b.y) + r).ceil() as i32 };
+		let rect = match (Rect { pos: min, size: (max - min).try_into().unwrap() }).intersection(full) {
+			None => continue,
+			Some(r) => r,
+		};
+		area = Some(area.map_or(rect, |a| a.union(rect)));
+
+		for ty in rect.pos.y / TILE_SIZE..=(rect.pos.y + rect.size.h as i32 - 1) / TILE_SIZE {
+			for tx in rect.pos.x / TILE_SIZE..=(rect.pos.x + rect.size.w as i32 - 1) / TILE_SIZE {
+				bins.entry((tx, ty)).or_default().push(first + i as u32);
+			}
+		}
+	}
+
+	for ((tx, ty), bin) in bins {
+		tiles.push(Tile {
+			origin: Point { x: tx * TILE_SIZE, y: ty * TILE_SIZE },
+			segment_start: segments.len() as u32,
+			segment_count: bin.len() as u32,
+		});
+		segments.extend(bin);
+	}
+
+	area
+}
+
+/// Area of `canvas` an image of `size` is placed in: centered, scaled down to fit keeping its aspect ratio.
+fn letterbox(size: Size, canvas: Size) -> Rect {
+	let scale = f32::min(1., f32::min(canvas.w as f32 / size.w as f32, canvas.h as f32 / size.h as f32));
+	let fitted = Size {
+		w: ((size.w as f32 * scale).round() as u32).clamp(1, canvas.w),
+		h: ((size.h as f32 * scale).round() as u32).clamp(1, canvas.h),
+	};
+	Rect {
+		pos: Point { x: ((canvas.w - fitted.w) / 2) as i32, y: ((canvas.h - fitted.h) / 2) as i32 },
+		size: fitted,
+	}
+}
+
+/// Whole pixels a selection dragged from `start` to `end` is moved by.
+fn move_offset(start: PointF, end: PointF) -> Point {
+	Point { x: (end.x - start.x).round() as i32, y: (end.y - start.y).round() as i32 }
+}
+
+fn create_layer_texture(ctx: &Context, size: Size) -> wgpu::Texture {
+	create_texture(
+		ctx,
+		"Canvas(Layer Texture)",
+		size,
+		wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST,
+	)
+}
+
+/// Both composite targets.
+fn create_composite_textures(ctx: &Context, size: Size) -> (wgpu::Texture, wgpu::Texture) {
+	let usage = wgpu::TextureUsages::RENDER_ATTACHMENT
+		| wgpu::TextureUsages::STORAGE_BINDING
+		| wgpu::TextureUsages::COPY_SRC
+		| wgpu::TextureUsages::COPY_DST;
+	(
+		create_texture(ctx, "Canvas(Composite Texture)", size, usage),
+		create_texture(ctx, "Canvas(Composite Scratch Texture)", size, usage),
+	)
+}
+
+/// Base copy of the active layer and the stroke mask.
+fn create_stroke_textures(ctx: &Context, size: Size) -> (wgpu::Texture, wgpu::Texture) {
+	let base = create_texture(
+		ctx,
+		"Canvas(Base Texture)",
+		size,
+		wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST,
+	);
+	let mask = create_texture(ctx, "Canvas(Stroke Mask)", size, wgpu::TextureUsages::STORAGE_BINDING);
+	(base, mask)
+}
+
+/// Everything is selected once it's cleared on the first render.
+fn create_selection_texture(ctx: &Context, size: Size) -> wgpu::Texture {
+	create_texture(ctx, "Canvas(Selection Texture)", size, wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_DST)
+}
+
+fn create_texture(ctx: &Context, label: &str, size: Size, usage: wgpu::TextureUsages) -> wgpu::Texture {
+	ctx.device.create_texture(&wgpu::TextureDescriptor {
+		label: Some(label),
+		size: size.into(),
+		mip_level_count: 1,
+		sample_count: 1,
+		dimension: wgpu::TextureDimension::D2,
+		format: wgpu::TextureFormat::Rgba8Unorm,
+		usage,
+	})
+}
+
+/// Reads back a region of an Rgba8 texture as tightly packed rows. Blocks until the GPU is done.
+fn read_texture(ctx: &Context, tex: &wgpu::Texture, rect: Rect) -> Vec<u8> {
+	let row_bytes = 4 * rect.size.w;
+	let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+	let padded_row_bytes = row_bytes.div_ceil(align) * align;
+
+	let buffer = ctx.device.create_buffer(&wgpu::BufferDescriptor {
+		label: Some("Canvas(Readback Buffer)"),
+		size: padded_row_bytes as u64 * rect.size.h as u64,
+		usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+		mapped_at_creation: false,
+	});
+
+	let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+		label: Some("Canvas(Readback Encoder)"),
+	});
+
+	encoder.copy_texture_to_buffer(
+		wgpu::ImageCopyTexture {
+			texture: tex,
+			mip_level: 0,
+			origin: wgpu::Origin3d { x: rect.pos.x as u32, y: rect.pos.y as u32, z: 0 },
+			aspect: wgpu::TextureAspect::All,
+		},
+		wgpu::ImageCopyBuffer {
+			buffer: &buffer,
+			layout: wgpu::ImageDataLayout {
+				offset: 0,
+				bytes_per_row: std::num::NonZeroU32::new(padded_row_bytes),
+				rows_per_image: None,
+			},
+		},
+		rect.size.into(),
+	);
+
+	ctx.queue.submit(std::iter::once(encoder.finish()));
+
+	let slice = buffer.slice(..);
+	slice.map_async(wgpu::MapMode::Read, |r| r.expect("Could not map readback buffer"));
+	ctx.device.poll(wgpu::Maintain::Wait);
+
+	let padded = slice.get_mapped_range();
+	let pixels = strip_row_padding(&padded, row_bytes as usize, padded_row_bytes as usize);
+	drop(padded);
+	buffer.unmap();
+
+	pixels
+}
+
+/// Tightly packed rows of `row_bytes` out of rows padded to `padded_row_bytes`.
+fn strip_row_padding(padded: &[u8], row_bytes: usize, padded_row_bytes: usize) -> Vec<u8> {
+	let mut pixels = Vec::with_capacity(padded.len() / padded_row_bytes * row_bytes);
+	for row in padded.chunks(padded_row_bytes) {
+		pixels.extend_from_slice(&row[..row_bytes]);
+	}
+	pixels
+}
+
+/// Uploads tightly packed Rgba8 rows into a region of a texture.
+fn write_texture(ctx: &Context, tex: &wgpu::Texture, rect: Rect, pixels: &[u8]) {
+	ctx.queue.write_texture(
+		wgpu::ImageCopyTexture {
+			texture: tex,
+			mip_level: 0,
+			origin: wgpu::Origin3d { x: rect.pos.x as u32, y: rect.pos.y as u32, z: 0 },
+			aspect: wgpu::TextureAspect::All,
+		},
+		pixels,
+		wgpu::ImageDataLayout {
+			offset: 0,
+			bytes_per_row: std::num::NonZeroU32::new(4 * rect.size.w),
+			rows_per_image: None,
+		},
+		rect.size.into(),
+	);
+}
+
+fn copy_region(encoder: &mut wgpu::CommandEncoder, src: &wgpu::Texture, src_pos: Point, dst: &wgpu::Texture, dst_pos: Point, size: Size) {
+	let image_copy = |texture, pos: Point| wgpu::ImageCopyTexture {
+		texture,
+		mip_level: 0,
+		origin: wgpu::Origin3d { x: pos.x as u32, y: pos.y as u32, z: 0 },
+		aspect: wgpu::TextureAspect::All,
+	};
+
+	encoder.copy_texture_to_texture(image_copy(src, src_pos), image_copy(dst, dst_pos), size.into());
 }
 
 impl components::Component for Canvas {
 	fn generate_pipelines(ctx: &Context) -> Pipelines {
-		let shader = ctx.device.create_shader_module(wgpu::include_wgsl!("shaders/canvas.wgsl"));
+		let shader = ctx.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+			label: Some("Canvas(Shader)"),
+			source: wgpu::ShaderSource::Wgsl(concat!(include_str!("shaders/blend.wgsl"), include_str!("shaders/canvas.wgsl")).into()),
+		});
 
 		let binding_group_layout = ctx.device.create_bind_group_layout(
 			&wgpu::BindGroupLayoutDescriptor {
@@ -52,23 +564,49 @@ impl components::Component for Canvas {
 			}
 		);
 
+		let storage_texture = |binding, access| wgpu::BindGroupLayoutEntry {
+			binding,
+			visibility: wgpu::ShaderStages::COMPUTE,
+			ty: wgpu::BindingType::StorageTexture {
+				access,
+				format: wgpu::TextureFormat::Rgba8Unorm,
+				view_dimension: wgpu::TextureViewDimension::D2
+			},
+			count: None,
+		};
+
+		let stroke_layout = ctx.device.create_bind_group_layout(
+			&wgpu::BindGroupLayoutDescriptor {
+				label: Some("Canvas(Stroke Layout)"),
+				entries: &[
+					storage_texture(0, wgpu::StorageTextureAccess::ReadWrite),
+					storage_texture(1, wgpu::StorageTextureAccess::ReadOnly),
+					storage_texture(2, wgpu::StorageTextureAccess::ReadWrite),
+					storage_texture(3, wgpu::StorageTextureAccess::ReadOnly),
+				]
+			}
+		);
+
+		let storage_buffer = |binding, element_size: usize| wgpu::BindGroupLayoutEntry {
+			binding,
+			visibility: wgpu::ShaderStages::COMPUTE,
+			ty: wgpu::BindingType::Buffer {
+				ty: wgpu::BufferBindingType::Storage {
+					read_only: true,
+				},
+				has_dynamic_offset: false,
+				min_binding_size: core::num::NonZeroU64::new(element_size as u64),
+			},
+			count: None,
+		};
+
 		let line_list_layout = ctx.device.create_bind_group_layout(
 			&wgpu::BindGroupLayoutDescriptor {
 				label: Some("Canvas(Line List Layout)"),
 				entries: &[
-					wgpu::BindGroupLayoutEntry {
-						binding: 0,
-						visibility: wgpu::ShaderStages::COMPUTE,
-						ty: wgpu::BindingType::Buffer {
-							ty: wgpu::BufferBindingType::Storage {
-								read_only: true,
-							},
-							has_dynamic_offset: false,
-							min_binding_size: core::num::NonZeroU64::new(4*2),
-
-						},
-						count: None,
-					}
+					storage_buffer(0, std::mem::size_of::<LinePoint>()),
+					storage_buffer(1, std::mem::size_of::<Tile>()),
+					storage_buffer(2, std::mem::size_of::<u32>()),
 				]
 			}
 		);
@@ -80,7 +618,7 @@ impl components::Component for Canvas {
 				push_constant_ranges: &[
 					wgpu::PushConstantRange {
 						stages: wgpu::ShaderStages::COMPUTE,
-						range: (0..12),
+						range: (0..8*4),
 					}
 				],
 			}
@@ -98,11 +636,11 @@ impl components::Component for Canvas {
 		let line_pipeline_layout = ctx.device.create_pipeline_layout(
 			&wgpu::PipelineLayoutDescriptor {
 				label: Some("Canvas(Line Pipeline Layout)"),
-				bind_group_layouts: &[&binding_group_layout, &line_list_layout],
+				bind_group_layouts: &[&stroke_layout, &line_list_layout],
 				push_constant_ranges: &[
 					wgpu::PushConstantRange {
 						stages: wgpu::ShaderStages::COMPUTE,
-						range: (0..9*4),
+						range: (0..12*4),
 					}
 				],
 			}
@@ -124,7 +662,7 @@ impl components::Component for Canvas {
 				push_constant_ranges: &[
 					wgpu::PushConstantRange {
 						stages: wgpu::ShaderStages::COMPUTE,
-						range: (0..12),
+						range: (0..8*4),
 					}
 				],
 			}
@@ -139,236 +677,2093 @@ impl components::Component for Canvas {
 			}
 		);
 
-		return Pipelines {
-			render: vec![],
-			compute: vec![clear_pipeline, point_pipeline, line_pipeline],
-		};
-	}
-	fn new(ctx: &mut Context) -> Box<Self> {
-		let tex_size = TEX_SIZE;
-		let tex = ctx.device.create_texture(&wgpu::TextureDescriptor {
-			label: Some("Canvas(Texture)"),
-			size: wgpu::Extent3d {
-				width: tex_size.w,
-				height: tex_size.h,
-				depth_or_array_layers: 1,
-			},
-			mip_level_count: 1,
-			sample_count: 1,
-			dimension: wgpu::TextureDimension::D2,
-			format: wgpu::TextureFormat::Rgba8Unorm,
-			usage: wgpu::TextureUsages::STORAGE_BINDING,
+		let composite_shader = ctx.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+			label: Some("Canvas(Composite Shader)"),
+			source: wgpu::ShaderSource::Wgsl(concat!(include_str!("shaders/blend.wgsl"), include_str!("shaders/composite.wgsl")).into()),
 		});
 
-		let pipelines = ctx.get_pipelines::<Self>();
+		let read_only_texture = |binding| wgpu::BindGroupLayoutEntry {
+			binding,
+			visibility: wgpu::ShaderStages::FRAGMENT,
+			ty: wgpu::BindingType::StorageTexture {
+				access: wgpu::StorageTextureAccess::ReadOnly,
+				format: wgpu::TextureFormat::Rgba8Unorm,
+				view_dimension: wgpu::TextureViewDimension::D2
+			},
+			count: None,
+		};
 
-		let line_buff = ctx.device.create_buffer(&wgpu::BufferDescriptor {
-			label: Some("Canvas(Line Buffer)"),
-			size: BUFF_SIZE.into(),
-			usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-			mapped_at_creation: false,
-		});
+		let composite_layout = ctx.device.create_bind_group_layout(
+			&wgpu::BindGroupLayoutDescriptor {
+				label: Some("Canvas(Composite Layout)"),
+				entries: &[read_only_texture(0), read_only_texture(1)],
+			}
+		);
 
-		let line_binding = ctx.device.create_bind_group(
-			&wgpu::BindGroupDescriptor {
-				label: Some("Canvas(Binding group 1)"),
-				layout: &pipelines.compute[2].get_bind_group_layout(1),
-				entries: &[
-					wgpu::BindGroupEntry {
-						binding: 0,
-						resource: line_buff.as_entire_binding(),
+		let composite_pipeline_layout = ctx.device.create_pipeline_layout(
+			&wgpu::PipelineLayoutDescriptor {
+				label: Some("Canvas(Composite Pipeline Layout)"),
+				bind_group_layouts: &[&composite_layout],
+				push_constant_ranges: &[
+					wgpu::PushConstantRange {
+						stages: wgpu::ShaderStages::FRAGMENT,
+						range: (0..std::mem::size_of::<LayerInput>() as u32),
 					}
 				],
 			}
 		);
 
-		let mut image = Image::new(ctx);
-		image.set_texture(ctx, tex);
-
-		Box::new(Self {
-			pipelines,
-			image,
-			tex_size,
-
-			line_buff,
-			line_binding,
+		let composite_pipeline = ctx.device.create_render_pipeline(
+			&wgpu::RenderPipelineDescriptor {
+				label: Some("Canvas(Composite Pipeline)"),
+				layout: Some(&composite_pipeline_layout),
+				vertex: wgpu::VertexState {
+					module: &composite_shader,
+					entry_point: "vs_main",
+					buffers: &[],
+				},
+				fragment: Some(wgpu::FragmentState {
+					module: &composite_shader,
+					entry_point: "fs_main",
+					targets: &[Some(wgpu::ColorTargetState {
+						format: wgpu::TextureFormat::Rgba8Unorm,
+						blend: None,
+						write_mask: wgpu::ColorWrites::ALL,
+					})],
+				}),
+				primitive: wgpu::PrimitiveState {
+					topology: wgpu::PrimitiveTopology::TriangleList,
+					strip_index_format: None,
+					front_face: wgpu::FrontFace::Ccw,
+					cull_mode: None,
+					polygon_mode: wgpu::PolygonMode::Fill,
+					unclipped_depth: false,
+					conservative: false,
+				},
+				depth_stencil: None,
+				multisample: wgpu::MultisampleState {
+					count: 1,
+					mask: !0,
+					alpha_to_coverage_enabled: false
+				},
+				multiview: None
+			}
+		);
 
-			brush_radius: BRUSH_RADIUS,
-			backgroud: BACKGROUND_COLOR,
-			line_points: VecDeque::new(),
-			mouse_pos: None,
-			mouse_down: false,
-			clear: true,
-		})
+		return Pipelines {
+			render: vec![composite_pipeline],
+			compute: vec![clear_pipeline, point_pipeline, line_pipeline],
+		};
+	}
+	fn new(ctx: &mut Context) -> Box<Self> {
+		Self::with_size(ctx, TEX_SIZE)
 	}
 
 	fn render(&mut self, encoder: &mut wgpu::CommandEncoder, ctx: &mut Context, output: &wgpu::TextureView, viewport: Rect, _clip_space: Option<Rect>) {
 
-		let tex_view = self.image
-			.get_texture()
-			.as_ref()
-			.unwrap()
-			.create_view(&wgpu::TextureViewDescriptor::default());
-
-		let binding_group = ctx.device.create_bind_group(
-			&wgpu::BindGroupDescriptor {
-				label: Some("Canvas(Binding group 0)"),
-				layout: &self.pipelines.compute[0].get_bind_group_layout(0),
-				entries: &[
-					wgpu::BindGroupEntry {
-						binding: 0,
-						resource: wgpu::BindingResource::TextureView(&tex_view),
-					},
-				],
+		let origin = Point { x: 0, y: 0 };
+		let full = Rect { pos: origin, size: self.tex_size };
+		// Changes recorded in this frame's encoder aren't visible to readbacks until it's submitted
+		let settled = !self.layers_pending();
+
+		// Content replacements are committed right away so a stroke in progress continues over them
+		if let Some((layer, upload_size, upload)) = self.upload.take() {
+			if let Some(index) = self.layer_index(layer) {
+				self.sync_base(encoder, ctx, layer);
+				let size = Size {
+					w: std::cmp::min(upload_size.w, self.tex_size.w),
+					h: std::cmp::min(upload_size.h, self.tex_size.h),
+				};
+				copy_region(encoder, &upload, origin, &self.layers[index].tex, origin, size);
+				// The image can't be represented in the log, the strokes below it are gone though
+				self.stroke_log.record(LogEntry::Clear { layer });
+				self.mark_dirty(full);
+				self.commit(encoder, ctx);
 			}
-		);
-
+		}
 
-		if self.clear {
-			self.clear = false;
-			let mut clear_pass = encoder.begin_compute_pass(
-				&wgpu::ComputePassDescriptor {
-					label: Some("Canvas(Clear Pass)"),
-				}
-			);
+		if self.selection_changed {
+			self.selection_changed = false;
+			self.upload_selection(encoder, ctx);
+		}
 
-			clear_pass.set_pipeline(&self.pipelines.compute[0]);
-			clear_pass.set_bind_group(0, &binding_group, &[]);
-			clear_pass.set_push_constants(0, bytemuck::cast_slice(&self.backgroud));
-			clear_pass.dispatch_workgroups((self.tex_size.w/8)+1, (self.tex_size.h/8)+1, 1);
+		// Moves are read back like fills, once everything queued before them was submitted
+		if settled {
+			if let Some(offset) = self.move_done.take() {
+				self.move_selection(ctx, offset);
+			}
 		}
 
-		if self.line_points.len() > 0 && self.line_points[0].len() > 1 {
+		// Filled shapes are read back, they're drawn once everything queued before them was submitted
+		if self.shape_done && settled && self.fill.is_none() {
+			self.shape_done = false;
+			if let Some(shape) = self.shape.take() {
+				self.draw_shape(ctx, shape);
+			}
+		}
 
-			// Lines that ended
-			let mut points_computed = 0;
-			let mut i = 0;
+		// Fills are raster only, they're left out of the stroke log
+		if let Some((layer, rect, fill)) = self.fill.take() {
+			if let Some(index) = self.layer_index(layer) {
+				self.sync_base(encoder, ctx, layer);
+				copy_region(encoder, &fill, origin, &self.layers[index].tex, rect.pos, rect.size);
+				self.mark_dirty(rect);
+				self.commit(encoder, ctx);
+			}
+		}
 
-			let mut mapped = ctx.staging_belt.write_buffer(encoder, &self.line_buff, 0, BUFF_SIZE, &ctx.device);
+		if self.clear {
+			self.clear = false;
+			let layer = self.layers[self.active].id;
+			self.sync_base(encoder, ctx, layer);
 
-			let mut bundles: VecDeque<(Rect, u32, u32)> = VecDeque::new();
+			let tex_view = self.layers[self.active].tex.create_view(&wgpu::TextureViewDescriptor::default());
+			let binding_group = self.texture_binding(ctx, &tex_view);
+			self.dispatch_clear(encoder, &binding_group, full, [0., 0., 0., 0.]);
 
-			let mut min_point: Point = self.line_points[0][0];
-			let mut max_point: Point = min_point.clone();
+			self.stroke_log.record(LogEntry::Clear { layer });
+			self.mark_dirty(full);
+			self.commit(encoder, ctx);
+		}
 
-			while points_computed < POINTS_PER_BUFF && i < self.line_points.len() {
-				use std::cmp::{min, max};
+		// Strokes with at least a segment, a stroke without one holds back the ones after it to keep their order
+		let ready = self.line_points.iter().take_while(|s| s.points.len() > 1).count();
 
-				let mut bundle: (Rect, u32, u32) = (Rect::new(0, 0, 0, 0), 0, 0);
+		if ready > 0 {
+			// All pending points are drawn this frame
+			let mut points: Vec<LinePoint> = Vec::new();
+			let mut tiles: Vec<Tile> = Vec::new();
+			let mut segments: Vec<u32> = Vec::new();
+			let mut batches: VecDeque<StrokeBatch> = VecDeque::new();
 
-				if self.line_points[i].len() <= 1 {
-					// Not a viable line
-					break;
-				}
+			for stroke in self.line_points.iter().take(ready) {
+				let first = points.len();
+				points.extend(stroke.points.iter());
 
-				bundle.1 = points_computed as u32;
+				let tile_start = tiles.len() as u32;
+				let area = bin_segments(&points[first..], first as u32, self.tex_size, &mut tiles, &mut segments);
+				batches.push_back(StrokeBatch { area, points: stroke.points.len(), tiles: tile_start..tiles.len() as u32 });
+			}
 
-				for k in 0..min(POINTS_PER_BUFF - points_computed, self.line_points[i].len()) {
-					let p = &self.line_points[i][k];
+			self.upload_lines(encoder, ctx, &points, &tiles, &segments);
 
-					const P_SIZE: usize = std::mem::size_of::<Point>();
+			while let Some(batch) = batches.pop_front() {
+				let layer = self.line_points[0].layer;
 
-					mapped[points_computed*P_SIZE..(points_computed+1)*P_SIZE].copy_from_slice(bytemuck::bytes_of(p));
-					points_computed += 1;
+				// Strokes on deleted layers are dropped
+				if let (Some(index), Some(area)) = (self.layer_index(layer), batch.area) {
+					self.sync_base(encoder, ctx, layer);
+					self.dispatch_stroke(encoder, ctx, index, &batch);
+					self.mark_dirty(area);
+				}
 
-					min_point.x = min(min_point.x, p.x);
-					min_point.y = min(min_point.y, p.y);
+				let mut to_be_removed = batch.points;
 
-					max_point.x = max(max_point.x, p.x);
-					max_point.y = max(max_point.y, p.y);
+				if self.line_points.len() == 1 && self.mouse_down {
+					to_be_removed -= 1;
 				}
 
-				let size = max_point - min_point;
+				self.line_points[0].points.drain(0..to_be_removed);
+
+				if self.line_points[0].points.is_empty() {
+					// Stroke finished, each one is its own history entry
+					let stroke = self.line_points.pop_front().unwrap();
+					if self.layer_index(stroke.layer).is_some() {
+						self.stroke_log.record(LogEntry::Stroke(LoggedStroke {
+							layer: stroke.layer,
+							points: stroke.recorded,
+							color: stroke.color,
+							opacity: stroke.opacity,
+							blend_mode: stroke.blend_mode,
+							erase: stroke.erase,
+						}));
+					}
+					self.commit(encoder, ctx);
+				}
+			}
+		}
 
-				bundle.0 = Rect { pos: min_point, size: size.try_into().unwrap() };
-				bundle.2 = points_computed as u32;
+		if self.line_points.is_empty() && !self.mouse_down {
+			self.commit(encoder, ctx);
+			self.apply_history_ops(encoder);
+		}
 
-				bundles.push_back(bundle);
+		// Only what changed is composited again
+		if let Some(rect) = self.damage.take().and_then(|d| d.intersection(full)) {
+			self.composite(encoder, ctx, rect);
+		}
+		self.presented = Some(self.view);
 
-				if points_computed == POINTS_PER_BUFF {
-					let f = file!();
-					eprintln!("Buffer filled on this frame. If there was aditional lines to draw they were postponed to the next frame -> Should increase FPS or {f}::POINTS_PER_BUFF");
-					break;
-				}
+		self.image.set_view(self.view);
+		self.image.render(encoder, ctx, output, viewport, Some(viewport));
 
-				i += 1;
+		let ants = match (&self.selecting, &self.selection) {
+			(Some(path), _) => {
+				let mut outline = self.selecting_outline(path);
+				outline.push(outline[0]);
+				Some(outline)
+			}
+			(None, Some(selection)) => {
+				// The outline follows a move in progress
+				let offset: PointF = match (self.moving, self.mouse_pos) {
+					(Some(start), Some(p)) => move_offset(start, p).into(),
+					_ => PointF { x: 0., y: 0. },
+				};
+				Some(selection.outline().iter().map(|&p| PointF { x: p.x + offset.x, y: p.y + offset.y }).collect())
 			}
+			(None, None) => None,
+		};
+		if let Some(outline) = ants {
+			// Whole pixels, the outline is only redrawn when they change
+			let phase = (self.ants_epoch.elapsed().as_secs_f32() * ANTS_SPEED).floor() % (2. * ANTS_DASH);
+			// Lassos have a vertex every texel, far more than needed to draw them
+			let outline = shape::simplify(&outline, 0.5 / self.view.scale);
+			self.ants.set_shape(ctx, outline, false, [0., 0., 0., 1.], 0.);
+			self.ants.set_dashes(ANTS_DASH, phase);
+			self.ants.set_view(self.view);
+			self.ants.render(encoder, ctx, output, viewport, Some(viewport));
+		}
+
+		if let Some(shape) = &self.shape {
+			let filled = self.fill_shapes && shape.is_closed();
+			let [r, g, b] = self.brush_color;
+			let radius = self.line_point(Sample { pos: PointF { x: 0., y: 0. }, pressure: 1. }).radius;
+			self.preview.set_shape(ctx, shape.outline(self.constrain, true), filled, [r, g, b, self.brush_opacity], radius);
+			self.preview.set_view(self.view);
+			self.preview.render(encoder, ctx, output, viewport, Some(viewport));
+		}
+	}
 
-			drop(mapped);
+	fn min_size() -> Option<components::Size> {
+		todo!()
+	}
+}
 
-			let mut compute_pass = encoder.begin_compute_pass(
-				&wgpu::ComputePassDescriptor {
-					label: Some("Canvas(Compute Pass)"),
-				}
-			);
+impl Canvas {
+	pub fn with_size(ctx: &mut Context, tex_size: Size) -> Box<Self> {
+		let (composite, composite_scratch) = create_composite_textures(ctx, tex_size);
+		let (base, mask) = create_stroke_textures(ctx, tex_size);
+
+		// New textures are zeroed, so the first layer and the base already match
+		let layer = Layer {
+			id: 0,
+			name: String::from("Layer 1"),
+			tex: create_layer_texture(ctx, tex_size),
+			visible: true,
+			opacity: 1.,
+			blend_mode: BlendMode::Normal,
+		};
 
-			compute_pass.set_pipeline(&self.pipelines.compute[2]);
-			compute_pass.set_bind_group(0, &binding_group, &[]);
-			compute_pass.set_bind_group(1, &self.line_binding, &[]);
-			compute_pass.set_push_constants(4*4, bytemuck::bytes_of(&self.brush_radius));
+		let pipelines = ctx.get_pipelines::<Self>();
 
+		let line_buff = GrowableBuffer::new(ctx, "Canvas(Line Buffer)", (INITIAL_LINE_CAPACITY * std::mem::size_of::<LinePoint>()) as u64);
+		let tile_buff = GrowableBuffer::new(ctx, "Canvas(Tile Buffer)", (INITIAL_LINE_CAPACITY * std::mem::size_of::<Tile>()) as u64);
+		let segment_buff = GrowableBuffer::new(ctx, "Canvas(Segment Buffer)", (INITIAL_LINE_CAPACITY * std::mem::size_of::<u32>()) as u64);
+		let line_binding = create_line_binding(ctx, &pipelines, [&line_buff, &tile_buff, &segment_buff]);
 
-			while !bundles.is_empty() {
-				let reference = bundles[0].0.pos - Point {x: self.brush_radius as i32, y: self.brush_radius as i32};
+		let mut image = Image::new(ctx);
+		image.set_texture(ctx, composite);
 
-				compute_pass.set_push_constants(0, bytemuck::bytes_of(&reference));
-				compute_pass.set_push_constants(4*2, bytemuck::bytes_of(&bundles[0].1));
-				compute_pass.set_push_constants(4*3, bytemuck::bytes_of(&bundles[0].2));
+		let preview = Preview::new(ctx);
+		let ants = Preview::new(ctx);
 
-				let mut drawing_area = bundles[0].0.size.clone();
-				drawing_area.w += 2*self.brush_radius;
-				drawing_area.h += 2*self.brush_radius;
+		Box::new(Self {
+			pipelines,
+			image,
+			preview,
+			ants,
+			ants_epoch: std::time::Instant::now(),
+			composite_scratch,
+			layers: vec![layer],
+			active: 0,
+			next_layer_id: 1,
+			base,
+			base_layer: Some(0),
+			mask,
+			tex_size,
 
+			line_buff,
+			tile_buff,
+			segment_buff,
+			line_binding,
+			stroke_bindings: HashMap::new(),
+			composite_bundles: None,
 
-				compute_pass.dispatch_workgroups(drawing_area.w/8 + 1, drawing_area.h/8 + 1, 1);
+			brush_radius: BRUSH_RADIUS,
+			brush_color: BRUSH_COLOR,
+			brush_opacity: BRUSH_OPACITY,
+			blend_mode: BlendMode::Normal,
+			linear_blending: false,
+			eraser: false,
+			antialias: true,
+			fill_tolerance: FILL_TOLERANCE,
+			shape_kind: None,
+			fill_shapes: false,
+			shape: None,
+			shape_done: false,
+			constrain: false,
+			select_kind: None,
+			selection: None,
+			selecting: None,
+			moving: None,
+			move_done: None,
+			duplicate: false,
+			selection_tex: create_selection_texture(ctx, tex_size),
+			selection_changed: true,
+			backgroud: BACKGROUND_COLOR,
+			line_points: VecDeque::new(),
+			view: ViewTransform::default(),
+			cursor: None,
+			panning: false,
+			mouse_pos: None,
+			pressure: 1.,
+			pressure_curve: PressureCurve::default(),
+			stabilizer: Stabilizer::default(),
+			mouse_down: false,
+			clear: false,
+			upload: None,
+			fill: None,
+
+			history: History::default(),
+			stroke_log: StrokeLog::default(),
+			history_ops: VecDeque::new(),
+			dirty: None,
+			damage: Some(Rect { pos: Point { x: 0, y: 0 }, size: tex_size }),
+			presented: None,
+		})
+	}
 
-				let mut to_be_removed = bundles[0].2 - bundles[0].1;
+	pub fn size(&self) -> Size {
+		self.tex_size
+	}
 
-				if self.line_points.len() == 1 && self.mouse_down {
-					to_be_removed -= 1;
-				}
+	/// Whether the next render would change what's shown, frames can be skipped otherwise.
+	pub fn needs_redraw(&self) -> bool {
+		let idle = self.line_points.is_empty() && !self.mouse_down;
+		self.damage.is_some()
+			|| self.upload.is_some()
+			|| self.fill.is_some()
+			|| self.shape.is_some()
+			|| self.shape_done
+			|| self.selecting.is_some()
+			|| self.moving.is_some()
+			|| self.move_done.is_some()
+			|| self.selection_changed
+			|| self.clear
+			|| self.line_points.front().is_some_and(|s| s.points.len() > 1)
+			|| (idle && !self.history_ops.is_empty())
+			|| self.presented != Some(self.view)
+	}
 
-				self.line_points[0].drain(0..(to_be_removed.try_into().unwrap()));
+	/// Reallocates the layer textures, keeping the existing content placed according to `anchor`.
+	/// New area is transparent. The undo history moves with the content, what could be redone is discarded.
+	pub fn resize(&mut self, ctx: &Context, size: Size, anchor: Anchor) {
+		let max_dim = ctx.device.limits().max_texture_dimension_2d;
+		let size = Size { w: size.w.clamp(1, max_dim), h: size.h.clamp(1, max_dim) };
+		let offset = anchor.offset(self.tex_size, size);
+		let origin = Point { x: 0, y: 0 };
 
-				if self.line_points[0].is_empty() {
-					self.line_points.pop_front();
-				}
+		let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+			label: Some("Canvas(Resize Encoder)"),
+		});
 
-				bundles.pop_front();
+		let old_area = Rect { pos: offset, size: self.tex_size };
+		let copied = old_area.intersection(Rect { pos: origin, size });
+		for layer in self.layers.iter_mut() {
+			let tex = create_layer_texture(ctx, size);
+			if let Some(dst) = copied {
+				copy_region(&mut encoder, &layer.tex, dst.pos - offset, &tex, dst.pos, dst.size);
 			}
+			layer.tex = tex;
 		}
 
-
-		self.image.render(encoder, ctx, output, Rect::new(0, 0, self.tex_size.w, self.tex_size.h), Some(viewport));
+		ctx.queue.submit(std::iter::once(encoder.finish()));
+
+		let (composite, composite_scratch) = create_composite_textures(ctx, size);
+		self.image.set_texture(ctx, composite);
+		self.composite_scratch = composite_scratch;
+
+		// The base is synced again before the next change
+		let (base, mask) = create_stroke_textures(ctx, size);
+		self.base = base;
+		self.base_layer = None;
+		self.mask = mask;
+		self.tex_size = size;
+		self.invalidate_bindings();
+
+		// Pending operations refer to the old texture coordinates
+		self.history.rebase(offset, size);
+		self.history_ops.clear();
+		self.dirty = None;
+		self.fill = None;
+		self.shape = None;
+		self.shape_done = false;
+		self.deselect();
+		self.move_done = None;
+		self.selection_tex = create_selection_texture(ctx, size);
+
+		self.stroke_log.rebase(offset);
+		for stroke in self.line_points.iter_mut() {
+			for p in stroke.points.iter_mut().chain(stroke.recorded.iter_mut()) {
+				p.pos += offset.into();
+			}
+		}
 	}
 
-	fn min_size() -> Option<components::Size> {
-		todo!()
+	pub fn mouse_pos(&mut self, p: PointF) {
+		self.pen_pos(p, 1.);
 	}
-}
 
-impl Canvas {
-	pub fn mouse_pos(&mut self, p: Point) {
-		if self.mouse_down && !self.line_points.is_empty() {
-			self.line_points.back_mut().unwrap().push_back(self.mouse_pos.unwrap());
+	/// Same as `mouse_pos` for devices that report pressure, which should be normalized to `0..=1`.
+	pub fn pen_pos(&mut self, p: PointF, pressure: f32) {
+		if self.panning {
+			if let Some(cursor) = self.cursor {
+				self.view.offset[0] += p.x - cursor.x;
+				self.view.offset[1] += p.y - cursor.y;
+			}
 		}
-		self.mouse_pos = Some(p);
-	}
+		self.cursor = Some(p);
+		self.mouse_pos = Some(self.view.texture_pos(p));
+		self.pressure = pressure;
 
-	pub fn mouse_up(&mut self) {
-		self.mouse_down = false;
-		if !self.line_points.is_empty() {
-			self.line_points.back_mut().unwrap().push_back(self.mouse_pos.unwrap());
+		if let Some(shape) = &mut self.shape {
+			shape.cursor = self.view.texture_pos(p);
 		}
-	}
 
-	pub fn mouse_down(&mut self) {
-		self.mouse_down = true;
-		self.line_points.push_back(VecDeque::new());
-		self.line_points.back_mut().unwrap().push_back(self.mouse_pos.unwrap());
-	}
+		if let (Some(path), Some(SelectionKind::Lasso)) = (&mut self.selecting, self.select_kind) {
+			let p = self.view.texture_pos(p);
+			let last = path[path.len() - 1];
+			if (p.x - last.x).hypot(p.y - last.y) >= 1. {
+				path.push(p);
+			}
+		}
+
+		if self.mouse_down && !self.line_points.is_empty() {
+			let samples = self.stabilizer.push(self.sample());
+			self.push_points(samples);
+		}
+	}
+
+	/// Scales the view by `ZOOM_STEP^steps`, keeping the point under the cursor in place.
+	pub fn zoom(&mut self, steps: f32) {
+		let cursor = match self.cursor {
+			None => return,
+			Some(c) => c,
+		};
+
+		let scale = (self.view.scale * ZOOM_STEP.powf(steps)).clamp(MIN_ZOOM, MAX_ZOOM);
+		let ratio = scale / self.view.scale;
+		self.view.offset[0] = cursor.x - (cursor.x - self.view.offset[0]) * ratio;
+		self.view.offset[1] = cursor.y - (cursor.y - self.view.offset[1]) * ratio;
+		self.view.scale = scale;
+
+		self.mouse_pos = Some(self.view.texture_pos(cursor));
+	}
+
+	/// While panning, cursor movement drags the view instead of only drawing.
+	pub fn set_panning(&mut self, panning: bool) {
+		self.panning = panning;
+	}
+
+	pub fn mouse_up(&mut self) {
+		if let Some(path) = self.selecting.take() {
+			let outline = self.selecting_outline(&path);
+			self.selection = Selection::new(outline, self.tex_size);
+			self.selection_changed = true;
+			return;
+		}
+
+		if let Some(start) = self.moving.take() {
+			if let Some(p) = self.mouse_pos {
+				let offset = move_offset(start, p);
+				if offset.x != 0 || offset.y != 0 {
+					self.move_done = Some(offset);
+				}
+			}
+			return;
+		}
+
+		if let Some(shape) = &mut self.shape {
+			match shape.kind {
+				ShapeKind::Polygon => shape.add_vertex(self.constrain),
+				_ => self.shape_done = true,
+			}
+			return;
+		}
+
+		self.mouse_down = false;
+		if !self.line_points.is_empty() {
+			let samples = self.stabilizer.end();
+			self.push_points(samples);
+		}
+	}
+
+	pub fn mouse_down(&mut self) {
+		if self.select_kind.is_some() {
+			// A move waiting for the next render would be lost
+			if self.move_done.is_some() {
+				return;
+			}
+			if let Some(p) = self.mouse_pos {
+				if self.selection.as_ref().is_some_and(|s| s.contains(p)) {
+					self.moving = Some(p);
+				} else {
+					self.selecting = Some(vec![p]);
+				}
+			}
+			return;
+		}
+
+		match self.shape_kind {
+			None => self.start_stroke(self.eraser),
+			Some(kind) => {
+				if self.shape.is_none() && !self.shape_done {
+					self.shape = self.mouse_pos.map(|p| Shape::new(kind, p));
+				}
+			}
+		}
+	}
+
+	/// Starts an eraser stroke regardless of the current tool.
+	pub fn erase_down(&mut self) {
+		self.start_stroke(true);
+	}
+
+	fn start_stroke(&mut self, erase: bool) {
+		self.mouse_down = true;
+		let sample = self.stabilizer.begin(self.sample());
+		let first = self.line_point(sample);
+		self.line_points.push_back(Stroke {
+			points: VecDeque::from([first]),
+			recorded: vec![first],
+			layer: self.layers[self.active].id,
+			color: self.brush_color,
+			opacity: self.brush_opacity,
+			blend_mode: self.blend_mode,
+			linear: self.linear_blending,
+			erase,
+		});
+	}
+
+	/// Switches between smooth and hard stroke edges.
+	pub fn toggle_antialias(&mut self) {
+		self.antialias = !self.antialias;
+	}
+
+	/// Switches the tool used by `mouse_down` between brush and eraser.
+	pub fn toggle_eraser(&mut self) {
+		self.eraser = !self.eraser;
+	}
+
+	/// Cycles the stabilizer between off, moving average and lazy brush.
+	pub fn cycle_stabilizer(&mut self) {
+		self.stabilizer.mode = self.stabilizer.mode.next();
+	}
+
+	/// Switches the spline resampling of stabilized points.
+	pub fn toggle_stroke_resampling(&mut self) {
+		self.stabilizer.resample = !self.stabilizer.resample;
+	}
+
+	/// Adds stabilized points to the last stroke.
+	fn push_points(&mut self, samples: Vec<Sample>) {
+		let points: Vec<LinePoint> = samples.into_iter().map(|s| self.line_point(s)).collect();
+		let stroke = self.line_points.back_mut().unwrap();
+		stroke.points.extend(points.iter().copied());
+		stroke.recorded.extend(points);
+	}
+
+	fn sample(&self) -> Sample {
+		Sample { pos: self.mouse_pos.unwrap(), pressure: self.pressure }
+	}
+
+	/// Stroke point with the current brush settings.
+	fn line_point(&self, s: Sample) -> LinePoint {
+		let (radius, opacity) = self.pressure_curve.map(s.pressure);
+		LinePoint {
+			pos: s.pos,
+			radius: self.brush_radius * radius,
+			opacity,
+		}
+	}
+
+	pub fn brush_radius(&self) -> f32 {
+		self.brush_radius
+	}
+
+	/// Radius used by points added from now on, so it can change in the middle of a stroke.
+	pub fn set_brush_radius(&mut self, radius: f32) {
+		self.brush_radius = radius.clamp(MIN_BRUSH_RADIUS, MAX_BRUSH_RADIUS);
+	}
+
+	pub fn brush_opacity(&self) -> f32 {
+		self.brush_opacity
+	}
+
+	/// Opacity used by strokes started from now on.
+	pub fn set_brush_opacity(&mut self, opacity: f32) {
+		self.brush_opacity = opacity.clamp(0., 1.);
+	}
+
+	pub fn blend_mode(&self) -> BlendMode {
+		self.blend_mode
+	}
+
+	/// Blend mode used by strokes started from now on.
+	pub fn set_blend_mode(&mut self, mode: BlendMode) {
+		self.blend_mode = mode;
+	}
+
+	/// Switches between blending sRGB encoded values (the usual behavior of painting programs) and linear light.
+	pub fn toggle_linear_blending(&mut self) {
+		self.linear_blending = !self.linear_blending;
+		self.invalidate_composite();
+	}
+
+	/// Color used by strokes started from now on. Strokes already in progress keep their color.
+	pub fn set_brush_color(&mut self, color: [f32; 3]) {
+		self.brush_color = color;
+	}
+
+	pub fn fill_tolerance(&self) -> u8 {
+		self.fill_tolerance
+	}
+
+	/// Maximum difference of each channel, out of 255, for a pixel to be filled.
+	pub fn set_fill_tolerance(&mut self, tolerance: u8) {
+		self.fill_tolerance = tolerance;
+	}
+
+	/// Whether changes to the layers are still queued for the next render. A layer read back before
+	/// they're submitted misses them, and writing the result back would undo them.
+	fn layers_pending(&self) -> bool {
+		self.upload.is_some()
+			|| self.fill.is_some()
+			|| self.clear
+			|| !self.line_points.is_empty()
+			|| !self.history_ops.is_empty()
+	}
+
+	/// Fills the area under the cursor on the active layer with the brush color and opacity.
+	/// The layer is read back right away, the result is applied on the next render.
+	pub fn fill(&mut self, ctx: &Context, mode: FillMode) {
+		// A layer read during a stroke would miss the part not drawn yet
+		if self.layers_pending() || self.mouse_down {
+			return;
+		}
+
+		let seed = match self.mouse_pos {
+			None => return,
+			Some(p) => Point { x: p.x.floor() as i32, y: p.y.floor() as i32 },
+		};
+
+		let full = Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size };
+		let layer = &self.layers[self.active];
+		let pixels = read_texture(ctx, &layer.tex, full);
+		let (mask, rect) = match fill::fill_mask(&pixels, self.tex_size, seed, self.fill_tolerance, mode) {
+			None => return,
+			Some(m) => m,
+		};
+
+		// Restricted to the selection like strokes
+		let w = self.tex_size.w as usize;
+		let coverage = |i: usize| mask[i] as u8 as f32 * self.selected(Point { x: (i % w) as i32, y: (i / w) as i32 });
+		let region = fill::fill_region(&pixels, self.tex_size, rect, self.brush_color, self.brush_opacity, coverage);
+		self.queue_fill(ctx, layer.id, rect, &region);
+	}
+
+	/// Cycles between freehand drawing and the shape tools, a shape being edited is discarded.
+	pub fn cycle_shape_tool(&mut self) {
+		self.cancel_shape();
+		self.select_kind = None;
+		self.shape_kind = ShapeKind::next(self.shape_kind);
+	}
+
+	pub fn shape_tool(&self) -> Option<ShapeKind> {
+		self.shape_kind
+	}
+
+	/// Switches between outlined and filled rectangles, ellipses and polygons.
+	pub fn toggle_shape_fill(&mut self) {
+		self.fill_shapes = !self.fill_shapes;
+	}
+
+	/// Snaps shapes to 45° lines, squares and circles while set.
+	pub fn set_constrain(&mut self, constrain: bool) {
+		self.constrain = constrain;
+	}
+
+	/// Draws the shape being edited on the next render, polygons are only finished this way.
+	pub fn finish_shape(&mut self) {
+		if self.shape.is_some() {
+			self.shape_done = true;
+		}
+	}
+
+	pub fn cancel_shape(&mut self) {
+		self.shape = None;
+		self.shape_done = false;
+	}
 
-	pub fn clear(&mut self) {
+	/// Outlines are drawn as a stroke with the brush settings. Filled shapes are painted with
+	/// the brush color and opacity on a readback of the layer, like bucket fills.
+	fn draw_shape(&mut self, ctx: &Context, shape: Shape) {
+		if shape.is_empty() {
+			return;
+		}
+
+		let outline = shape.outline(self.constrain, false);
+		let layer = self.layers[self.active].id;
+
+		if self.fill_shapes && shape.is_closed() {
+			let rect = match shape::bounds(&outline, self.tex_size) {
+				None => return,
+				Some(r) => r,
+			};
+			let pixels = read_texture(ctx, &self.layers[self.active].tex, rect);
+			let coverage = shape::polygon_coverage(&outline, rect);
+			let local = Rect { pos: Point { x: 0, y: 0 }, size: rect.size };
+			let w = rect.size.w as usize;
+			let coverage = |i: usize| coverage[i] * self.selected(rect.pos + Point { x: (i % w) as i32, y: (i / w) as i32 });
+			let region = fill::fill_region(&pixels, rect.size, local, self.brush_color, self.brush_opacity, coverage);
+			self.queue_fill(ctx, layer, rect, &region);
+		} else {
+			let points: Vec<LinePoint> = shape::subdivide(&outline)
+				.into_iter()
+				.map(|p| self.line_point(Sample { pos: p, pressure: 1. }))
+				.collect();
+			self.line_points.push_back(Stroke {
+				points: points.iter().copied().collect(),
+				recorded: points,
+				layer,
+				color: self.brush_color,
+				opacity: self.brush_opacity,
+				blend_mode: self.blend_mode,
+				linear: self.linear_blending,
+				erase: false,
+			});
+		}
+	}
+
+	/// Cycles between drawing and the selection tools, a shape being edited is discarded.
+	pub fn cycle_selection_tool(&mut self) {
+		self.cancel_shape();
+		self.shape_kind = None;
+		self.select_kind = SelectionKind::next(self.select_kind);
+	}
+
+	pub fn selection_tool(&self) -> Option<SelectionKind> {
+		self.select_kind
+	}
+
+	pub fn has_selection(&self) -> bool {
+		self.selection.is_some()
+	}
+
+	/// When the selection outline should be redrawn next, once its dashes have moved a whole pixel.
+	/// `None` if there's no outline.
+	pub fn next_animation_frame(&self) -> Option<std::time::Instant> {
+		if self.selection.is_none() && self.selecting.is_none() {
+			return None;
+		}
+		let moved = self.ants_epoch.elapsed().as_secs_f32() * ANTS_SPEED;
+		Some(self.ants_epoch + std::time::Duration::from_secs_f32((moved.floor() + 1.) / ANTS_SPEED))
+	}
+
+	/// Moving the selection leaves a copy of the selected pixels in place while set.
+	pub fn set_duplicate(&mut self, duplicate: bool) {
+		self.duplicate = duplicate;
+	}
+
+	/// Drops the selection, painting is allowed everywhere again.
+	pub fn deselect(&mut self) {
+		self.selection = None;
+		self.selecting = None;
+		self.moving = None;
+		self.selection_changed = true;
+	}
+
+	/// Makes the selected pixels of the active layer transparent.
+	/// The layer is read back right away, the result is applied on the next render.
+	pub fn delete_selection(&mut self, ctx: &Context) {
+		// A layer read during a stroke would miss the part not drawn yet
+		if self.layers_pending() || self.mouse_down {
+			return;
+		}
+		let selection = match &self.selection {
+			None => return,
+			Some(s) => s,
+		};
+
+		let rect = selection.rect();
+		let layer = &self.layers[self.active];
+		let mut pixels = read_texture(ctx, &layer.tex, rect);
+		selection::erase_pixels(&mut pixels, rect, selection);
+		self.queue_fill(ctx, layer.id, rect, &pixels);
+	}
+
+	/// Moves the selected pixels of the active layer by `offset`, and the selection with them.
+	fn move_selection(&mut self, ctx: &Context, offset: Point) {
+		let selection = match &self.selection {
+			None => return,
+			Some(s) => s,
+		};
+
+		let full = Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size };
+		let src = selection.rect();
+		let area = match src.union(src + offset).intersection(full) {
+			None => return,
+			Some(a) => a,
+		};
+
+		let layer = &self.layers[self.active];
+		let mut pixels = read_texture(ctx, &layer.tex, area);
+		selection::move_pixels(&mut pixels, area, selection, offset, self.duplicate);
+		let moved = selection.moved(offset, self.tex_size);
+		self.queue_fill(ctx, layer.id, area, &pixels);
+
+		self.selection = moved;
+		self.selection_changed = true;
+	}
+
+	/// Closed outline of the selection being made from `path`, the press position followed by the lasso path.
+	fn selecting_outline(&self, path: &[PointF]) -> Vec<PointF> {
+		let start = path[0];
+		match (self.select_kind, self.mouse_pos) {
+			(Some(SelectionKind::Rectangle), Some(end)) => {
+				vec![start, PointF { x: end.x, y: start.y }, end, PointF { x: start.x, y: end.y }]
+			}
+			_ => path.to_vec(),
+		}
+	}
+
+	/// Selected fraction of the pixel at `p`, everything is selected without a selection.
+	fn selected(&self, p: Point) -> f32 {
+		self.selection.as_ref().map_or(1., |s| s.coverage(p))
+	}
+
+	/// Writes the selection to the selection texture.
+	fn upload_selection(&self, encoder: &mut wgpu::CommandEncoder, ctx: &Context) {
+		let origin = Point { x: 0, y: 0 };
+		let full = Rect { pos: origin, size: self.tex_size };
+		let view = self.selection_tex.create_view(&wgpu::TextureViewDescriptor::default());
+		let binding_group = self.texture_binding(ctx, &view);
+
+		let selection = match &self.selection {
+			None => return self.dispatch_clear(encoder, &binding_group, full, [1., 1., 1., 1.]),
+			Some(s) => s,
+		};
+		self.dispatch_clear(encoder, &binding_group, full, [0., 0., 0., 0.]);
+
+		// Copied from another texture so it's ordered after the clear
+		let rect = selection.rect();
+		let mask = create_texture(ctx, "Canvas(Selection Upload Texture)", rect.size, wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST);
+		write_texture(ctx, &mask, Rect { pos: origin, size: rect.size }, &selection.mask());
+		copy_region(encoder, &mask, origin, &self.selection_tex, rect.pos, rect.size);
+	}
+
+	/// Uploads the new pixels of `rect` to be copied to a layer on the next render,
+	/// so they're ordered with the strokes and the history.
+	fn queue_fill(&mut self, ctx: &Context, layer: u32, rect: Rect, pixels: &[u8]) {
+		let tex = create_texture(ctx, "Canvas(Fill Texture)", rect.size, wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST);
+		write_texture(ctx, &tex, Rect { pos: Point { x: 0, y: 0 }, size: rect.size }, pixels);
+		self.fill = Some((layer, rect, tex));
+	}
+
+	/// Makes the active layer transparent, only the selected part of it if there's a selection.
+	pub fn clear(&mut self, ctx: &Context) {
+		if self.has_selection() {
+			return self.delete_selection(ctx);
+		}
 		self.clear = true;
 	}
+
+	pub fn layers(&self) -> &[Layer] {
+		&self.layers
+	}
+
+	pub fn active_layer(&self) -> usize {
+		self.active
+	}
+
+	/// Adds a transparent layer above the active one and makes it active.
+	pub fn add_layer(&mut self, ctx: &Context) {
+		let id = self.next_layer_id;
+		self.next_layer_id += 1;
+
+		self.active += 1;
+		self.layers.insert(self.active, Layer {
+			id,
+			name: format!("Layer {}", id + 1),
+			tex: create_layer_texture(ctx, self.tex_size),
+			visible: true,
+			opacity: 1.,
+			blend_mode: BlendMode::Normal,
+		});
+		self.invalidate_composite();
+	}
+
+	/// Removes the active layer, the last one is never removed.
+	/// Its history entries are kept but have no effect.
+	pub fn delete_layer(&mut self) {
+		if self.layers.len() == 1 {
+			return;
+		}
+		let layer = self.layers.remove(self.active);
+		self.stroke_bindings.remove(&layer.id);
+		self.active = self.active.saturating_sub(1);
+		self.invalidate_composite();
+	}
+
+	/// Makes the layer at `index` (bottom to top) active.
+	pub fn select_layer(&mut self, index: usize) {
+		self.active = index.min(self.layers.len() - 1);
+	}
+
+	/// Moves the active layer `steps` positions up the stack, or down if negative.
+	pub fn move_layer(&mut self, steps: isize) {
+		let index = self.active.saturating_add_signed(steps).min(self.layers.len() - 1);
+		let layer = self.layers.remove(self.active);
+		self.layers.insert(index, layer);
+		self.active = index;
+		self.invalidate_composite();
+	}
+
+	pub fn toggle_layer_visibility(&mut self) {
+		let layer = &mut self.layers[self.active];
+		layer.visible = !layer.visible;
+		self.invalidate_composite();
+	}
+
+	pub fn set_layer_opacity(&mut self, opacity: f32) {
+		self.layers[self.active].opacity = opacity.clamp(0., 1.);
+		self.invalidate_composite();
+	}
+
+	/// Blend mode the active layer is composited with over the layers below it.
+	pub fn set_layer_blend_mode(&mut self, mode: BlendMode) {
+		self.layers[self.active].blend_mode = mode;
+		self.invalidate_composite();
+	}
+
+	/// Writes the composited layers, over the background color, to a PNG file.
+	pub fn export_png(&self, ctx: &Context, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+		let tex = self.image.get_texture().as_ref().unwrap();
+		let pixels = read_texture(ctx, tex, Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size });
+
+		std::fs::write(path, project::encode_png(self.tex_size, &pixels)?)
+	}
+
+	/// Replaces the content of the active layer with an image file, letterboxed: images bigger than the canvas
+	/// are scaled down keeping their aspect ratio, and the image is centered. The uncovered area is left transparent.
+	pub fn load_image(&mut self, ctx: &Context, path: impl AsRef<std::path::Path>) -> image::ImageResult<()> {
+		let img = image::open(path)?;
+		let area = letterbox(Size { w: img.width(), h: img.height() }, self.tex_size);
+		let img = if (area.size.w, area.size.h) == (img.width(), img.height()) {
+			img.into_rgba8()
+		} else {
+			img.resize_exact(area.size.w, area.size.h, image::imageops::FilterType::Triangle).into_rgba8()
+		};
+
+		let mut layer = image::RgbaImage::new(self.tex_size.w, self.tex_size.h);
+		image::imageops::replace(&mut layer, &img, area.pos.x as i64, area.pos.y as i64);
+
+		// Uploaded to its own texture and copied to the layer on the next render,
+		// so it's ordered with the strokes and the history
+		let full = Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size };
+		let upload = create_texture(ctx, "Canvas(Upload Texture)", self.tex_size, wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST);
+		write_texture(ctx, &upload, full, layer.as_raw());
+		self.upload = Some((self.layers[self.active].id, self.tex_size, upload));
+
+		Ok(())
+	}
+
+	/// Loads a project or OpenRaster file depending on the extension of `path`, otherwise an image into the active layer.
+	pub fn open(&mut self, ctx: &Context, path: impl AsRef<std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+		let path = path.as_ref();
+		match path.extension() {
+			Some(e) if e == PROJECT_EXTENSION => self.load_project(ctx, path)?,
+			Some(e) if e == ORA_EXTENSION => self.import_ora(ctx, path)?,
+			_ => self.load_image(ctx, path)?,
+		}
+		Ok(())
+	}
+
+	/// Saves the layers and the brush settings to a project file, see `project.rs`.
+	pub fn save_project(&self, ctx: &Context, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+		let project = Project {
+			size: self.tex_size,
+			background: self.backgroud,
+			active: self.active,
+			brush: BrushSettings {
+				radius: self.brush_radius,
+				color: self.brush_color,
+				opacity: self.brush_opacity,
+				blend_mode: self.blend_mode,
+				antialias: self.antialias,
+				linear_blending: self.linear_blending,
+				eraser: self.eraser,
+			},
+			layers: self.read_layers(ctx),
+			// Layer ids become indices, entries of deleted layers are dropped
+			strokes: self.stroke_log.entries().iter().filter_map(|e| {
+				let index = self.layer_index(e.layer())? as u32;
+				Some(match e {
+					LogEntry::Clear { .. } => LogEntry::Clear { layer: index },
+					LogEntry::Stroke(s) => LogEntry::Stroke(LoggedStroke { layer: index, ..s.clone() }),
+				})
+			}).collect(),
+		};
+
+		project.write(std::io::BufWriter::new(std::fs::File::create(path)?))
+	}
+
+	/// Replaces the layers and the brush settings with the ones of a project file.
+	/// Strokes in progress and the undo history are discarded.
+	pub fn load_project(&mut self, ctx: &Context, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+		let max_dimension = ctx.device.limits().max_texture_dimension_2d;
+		let project = Project::read(std::io::BufReader::new(std::fs::File::open(path)?), max_dimension)?;
+
+		self.replace_layers(ctx, project.size, project.layers)?;
+		self.active = project.active;
+		// Layers get their index as id
+		self.stroke_log = StrokeLog::load(project.strokes);
+
+		self.backgroud = project.background;
+		self.brush_radius = project.brush.radius.clamp(MIN_BRUSH_RADIUS, MAX_BRUSH_RADIUS);
+		self.brush_color = project.brush.color;
+		self.brush_opacity = project.brush.opacity.clamp(0., 1.);
+		self.blend_mode = project.brush.blend_mode;
+		self.antialias = project.brush.antialias;
+		self.linear_blending = project.brush.linear_blending;
+		self.eraser = project.brush.eraser;
+
+		Ok(())
+	}
+
+	/// Writes the stroke log of the visible layers as an SVG file.
+	pub fn export_svg(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+		std::fs::write(path, self.stroke_log.svg(self.tex_size, self.backgroud, &self.layers))
+	}
+
+	/// Writes the layers to an OpenRaster file, along with the composited image.
+	pub fn export_ora(&self, ctx: &Context, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+		let full = Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size };
+		let merged = read_texture(ctx, self.image.get_texture().as_ref().unwrap(), full);
+		let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+		openraster::write(file, self.tex_size, &self.read_layers(ctx), &merged)
+	}
+
+	/// Replaces the layers with the ones of an OpenRaster file, the top one becomes active.
+	/// Strokes in progress and the undo history are discarded.
+	pub fn import_ora(&mut self, ctx: &Context, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+		let (size, layers) = openraster::read(
+			std::io::BufReader::new(std::fs::File::open(path)?),
+			ctx.device.limits().max_texture_dimension_2d,
+		)?;
+		self.replace_layers(ctx, size, layers)?;
+		self.active = self.layers.len() - 1;
+		Ok(())
+	}
+
+	/// Reads back the content and properties of every layer.
+	fn read_layers(&self, ctx: &Context) -> Vec<ProjectLayer> {
+		let full = Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size };
+		self.layers.iter().map(|l| ProjectLayer {
+			name: l.name.clone(),
+			visible: l.visible,
+			opacity: l.opacity,
+			blend_mode: l.blend_mode,
+			pixels: read_texture(ctx, &l.tex, full),
+		}).collect()
+	}
+
+	/// Resizes the canvas and replaces all layers, discarding strokes in progress and the undo history.
+	fn replace_layers(&mut self, ctx: &Context, size: Size, layers: Vec<ProjectLayer>) -> std::io::Result<()> {
+		let max_dim = ctx.device.limits().max_texture_dimension_2d;
+		if size.w > max_dim || size.h > max_dim {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Image is bigger than the device allows"));
+		}
+
+		self.resize(ctx, size, Anchor::TOP_LEFT);
+
+		let full = Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size };
+		self.layers = layers.into_iter().enumerate().map(|(id, l)| {
+			let tex = create_layer_texture(ctx, self.tex_size);
+			write_texture(ctx, &tex, full, &l.pixels);
+			Layer {
+				id: id as u32,
+				name: l.name,
+				tex,
+				visible: l.visible,
+				opacity: l.opacity,
+				blend_mode: l.blend_mode,
+			}
+		}).collect();
+		self.next_layer_id = self.layers.len() as u32;
+		self.active = 0;
+		self.base_layer = None;
+		self.invalidate_bindings();
+		self.stroke_log = StrokeLog::default();
+		// Snapshots and pending fills refer to layer ids of the previous document
+		self.history = History::default();
+		self.history_ops.clear();
+		self.fill = None;
+		self.deselect();
+
+		self.line_points.clear();
+		self.mouse_down = false;
+		self.clear = false;
+		self.upload = None;
+
+		Ok(())
+	}
+
+	/// Reverts the last stroke (or clear). Applied on the next render once no stroke is in progress.
+	/// The selection is dropped when it's applied.
+	pub fn undo(&mut self) {
+		self.history_ops.push_back(HistoryOp::Undo);
+	}
+
+	/// Reapplies the last undone operation. Applied on the next render once no stroke is in progress.
+	/// The selection is dropped when it's applied.
+	pub fn redo(&mut self) {
+		self.history_ops.push_back(HistoryOp::Redo);
+	}
+
+	fn texture_binding(&self, ctx: &Context, tex_view: &wgpu::TextureView) -> wgpu::BindGroup {
+		ctx.device.create_bind_group(
+			&wgpu::BindGroupDescriptor {
+				label: Some("Canvas(Binding group 0)"),
+				layout: &self.pipelines.compute[0].get_bind_group_layout(0),
+				entries: &[
+					wgpu::BindGroupEntry {
+						binding: 0,
+						resource: wgpu::BindingResource::TextureView(tex_view),
+					},
+				],
+			}
+		)
+	}
+
+	/// Fills `rect` of the texture bound in `binding_group` with `color`.
+	fn dispatch_clear(&self, encoder: &mut wgpu::CommandEncoder, binding_group: &wgpu::BindGroup, rect: Rect, color: [f32; 4]) {
+		let mut clear_pass = encoder.begin_compute_pass(
+			&wgpu::ComputePassDescriptor {
+				label: Some("Canvas(Clear Pass)"),
+			}
+		);
+
+		clear_pass.set_pipeline(&self.pipelines.compute[0]);
+		clear_pass.set_bind_group(0, binding_group, &[]);
+		clear_pass.set_push_constants(0, bytemuck::cast_slice(&color));
+		clear_pass.set_push_constants(4*4, bytemuck::bytes_of(&rect.pos));
+		clear_pass.set_push_constants(4*6, bytemuck::bytes_of(&rect.size));
+		clear_pass.dispatch_workgroups(rect.size.w.div_ceil(8), rect.size.h.div_ceil(8), 1);
+	}
+
+	fn mark_dirty(&mut self, r: Rect) {
+		self.dirty = Some(self.dirty.map_or(r, |d| d.union(r)));
+		self.mark_damaged(r);
+	}
+
+	/// Records a change to be composited on the next render.
+	fn mark_damaged(&mut self, r: Rect) {
+		self.damage = Some(self.damage.map_or(r, |d| d.union(r)));
+	}
+
+	/// Records everything drawn on the base layer since the last commit as a single history entry.
+	fn commit(&mut self, encoder: &mut wgpu::CommandEncoder, ctx: &Context) {
+		let full = Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size };
+		let rect = match self.dirty.take().and_then(|d| d.intersection(full)) {
+			None => return,
+			Some(r) => r,
+		};
+
+		let (layer, index) = match self.base_layer.and_then(|id| Some((id, self.layer_index(id)?))) {
+			None => return,
+			Some(l) => l,
+		};
+
+		let usage = wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST;
+		let before = create_texture(ctx, "Canvas(Undo Texture)", rect.size, usage);
+		let after = create_texture(ctx, "Canvas(Redo Texture)", rect.size, usage);
+
+		let origin = Point { x: 0, y: 0 };
+		let tex = &self.layers[index].tex;
+		copy_region(encoder, &self.base, rect.pos, &before, origin, rect.size);
+		copy_region(encoder, tex, rect.pos, &after, origin, rect.size);
+		copy_region(encoder, tex, rect.pos, &self.base, rect.pos, rect.size);
+
+		let mask_view = self.mask.create_view(&wgpu::TextureViewDescriptor::default());
+		let mask_binding = self.texture_binding(ctx, &mask_view);
+		self.dispatch_clear(encoder, &mask_binding, rect, [0., 0., 0., 0.]);
+
+		let log = self.stroke_log.commit();
+		self.history.push(Snapshot { layer, rect, clip: Some(rect), before, after, log });
+	}
+
+	/// Makes `base` mirror a layer before it gets modified, committing what was drawn on the previous one.
+	fn sync_base(&mut self, encoder: &mut wgpu::CommandEncoder, ctx: &Context, layer: u32) {
+		if self.base_layer == Some(layer) {
+			return;
+		}
+
+		self.commit(encoder, ctx);
+
+		if let Some(index) = self.layer_index(layer) {
+			let origin = Point { x: 0, y: 0 };
+			copy_region(encoder, &self.layers[index].tex, origin, &self.base, origin, self.tex_size);
+			self.base_layer = Some(layer);
+		}
+	}
+
+	fn apply_history_ops(&mut self, encoder: &mut wgpu::CommandEncoder) {
+		let mut applied = false;
+		while let Some(op) = self.history_ops.pop_front() {
+			let restored = match op {
+				HistoryOp::Undo => self.history.undo().map(|s| (s.layer, s.rect, s.clip, &s.before, s.log.0)),
+				HistoryOp::Redo => self.history.redo().map(|s| (s.layer, s.rect, s.clip, &s.after, s.log.1)),
+			};
+
+			let (layer, rect, clip, src, log_len) = match restored {
+				None => continue,
+				Some(r) => r,
+			};
+			self.stroke_log.set_len(log_len);
+			applied = true;
+
+			// Entries of deleted layers, or cut off by a resize, have nothing to restore
+			let clip = match clip {
+				None => continue,
+				Some(c) => c,
+			};
+			let src_pos = clip.pos - rect.pos;
+			if let Some(l) = self.layers.iter().find(|l| l.id == layer) {
+				copy_region(encoder, src, src_pos, &l.tex, clip.pos, clip.size);
+				if l.visible {
+					self.damage = Some(self.damage.map_or(clip, |d| d.union(clip)));
+				}
+			}
+			if self.base_layer == Some(layer) {
+				copy_region(encoder, src, src_pos, &self.base, clip.pos, clip.size);
+			}
+		}
+
+		// A moved selection would be left where the restored pixels no longer are
+		if applied && self.selection.is_some() {
+			self.deselect();
+		}
+	}
+
+	/// Writes the points and tiles of the strokes drawn this frame to the line buffers, growing them as needed.
+	fn upload_lines(&mut self, encoder: &mut wgpu::CommandEncoder, ctx: &mut Context, points: &[LinePoint], tiles: &[Tile], segments: &[u32]) {
+		let points: &[u8] = bytemuck::cast_slice(points);
+		let tiles: &[u8] = bytemuck::cast_slice(tiles);
+		let segments: &[u8] = bytemuck::cast_slice(segments);
+
+		let mut reallocated = self.line_buff.reserve(ctx, points.len() as u64);
+		reallocated |= self.tile_buff.reserve(ctx, tiles.len() as u64);
+		reallocated |= self.segment_buff.reserve(ctx, segments.len() as u64);
+		if reallocated {
+			self.line_binding = create_line_binding(ctx, &self.pipelines, [&self.line_buff, &self.tile_buff, &self.segment_buff]);
+		}
+
+		self.line_buff.write(encoder, ctx, points);
+		self.tile_buff.write(encoder, ctx, tiles);
+		self.segment_buff.write(encoder, ctx, segments);
+	}
+
+	fn layer_index(&self, id: u32) -> Option<usize> {
+		self.layers.iter().position(|l| l.id == id)
+	}
+
+	/// Draws a bundle of points of the first stroke in `line_points` on a layer.
+	fn dispatch_stroke(&mut self, encoder: &mut wgpu::CommandEncoder, ctx: &Context, layer: usize, batch: &StrokeBatch) {
+		let id = self.layers[layer].id;
+		if !self.stroke_bindings.contains_key(&id) {
+			let binding = self.stroke_binding(ctx, &self.layers[layer]);
+			self.stroke_bindings.insert(id, binding);
+		}
+		let stroke_binding = &self.stroke_bindings[&id];
+
+		let mut compute_pass = encoder.begin_compute_pass(
+			&wgpu::ComputePassDescriptor {
+				label: Some("Canvas(Compute Pass)"),
+			}
+		);
+
+		compute_pass.set_pipeline(&self.pipelines.compute[2]);
+		compute_pass.set_bind_group(0, stroke_binding, &[]);
+		compute_pass.set_bind_group(1, &self.line_binding, &[]);
+		compute_pass.set_push_constants(4*8, bytemuck::bytes_of(&(self.antialias as u32)));
+
+		let tile_count = batch.tiles.end - batch.tiles.start;
+		compute_pass.set_push_constants(0, bytemuck::bytes_of(&batch.tiles.start));
+		compute_pass.set_push_constants(4, bytemuck::bytes_of(&tile_count));
+
+		let stroke = &self.line_points[0];
+		let [r, g, b] = stroke.color;
+		compute_pass.set_push_constants(4*4, bytemuck::cast_slice(&[r, g, b, stroke.opacity]));
+		compute_pass.set_push_constants(4*9, bytemuck::bytes_of(&(stroke.blend_mode as u32)));
+		compute_pass.set_push_constants(4*10, bytemuck::bytes_of(&(stroke.linear as u32)));
+		compute_pass.set_push_constants(4*11, bytemuck::bytes_of(&(stroke.erase as u32)));
+
+		// One workgroup per tile, wrapped into rows when there are more than a dimension allows
+		let max_workgroups = ctx.device.limits().max_compute_workgroups_per_dimension;
+		let columns = tile_count.min(max_workgroups);
+		compute_pass.dispatch_workgroups(columns, tile_count.div_ceil(columns), 1);
+	}
+
+	fn stroke_binding(&self, ctx: &Context, layer: &Layer) -> wgpu::BindGroup {
+		let tex_view = layer.tex.create_view(&wgpu::TextureViewDescriptor::default());
+		let base_view = self.base.create_view(&wgpu::TextureViewDescriptor::default());
+		let mask_view = self.mask.create_view(&wgpu::TextureViewDescriptor::default());
+		let selection_view = self.selection_tex.create_view(&wgpu::TextureViewDescriptor::default());
+		ctx.device.create_bind_group(
+			&wgpu::BindGroupDescriptor {
+				label: Some("Canvas(Stroke Binding group 0)"),
+				layout: &self.pipelines.compute[2].get_bind_group_layout(0),
+				entries: &[
+					wgpu::BindGroupEntry {
+						binding: 0,
+						resource: wgpu::BindingResource::TextureView(&tex_view),
+					},
+					wgpu::BindGroupEntry {
+						binding: 1,
+						resource: wgpu::BindingResource::TextureView(&base_view),
+					},
+					wgpu::BindGroupEntry {
+						binding: 2,
+						resource: wgpu::BindingResource::TextureView(&mask_view),
+					},
+					wgpu::BindGroupEntry {
+						binding: 3,
+						resource: wgpu::BindingResource::TextureView(&selection_view),
+					},
+				],
+			}
+		)
+	}
+
+	/// Drops the cached bind groups and bundles, for when textures are reallocated.
+	fn invalidate_bindings(&mut self) {
+		self.stroke_bindings.clear();
+		self.invalidate_composite();
+	}
+
+	/// Drops the composite bundles, everything is composited again on the next render.
+	fn invalidate_composite(&mut self) {
+		self.composite_bundles = None;
+		self.mark_damaged(Rect { pos: Point { x: 0, y: 0 }, size: self.tex_size });
+	}
+
+	/// Records the composite pass of every visible layer. Each one reads the result so far from one target
+	/// and is executed in a pass writing to the other, starting from the image texture.
+	fn record_composite_bundles(&self, ctx: &Context) -> Vec<wgpu::RenderBundle> {
+		let targets = [self.image.get_texture().as_ref().unwrap(), &self.composite_scratch];
+		let views = targets.map(|t| t.create_view(&wgpu::TextureViewDescriptor::default()));
+
+		let mut current = 0;
+		let mut bundles = Vec::new();
+		for layer in self.layers.iter().filter(|l| l.visible) {
+			let layer_view = layer.tex.create_view(&wgpu::TextureViewDescriptor::default());
+			let binding_group = ctx.device.create_bind_group(
+				&wgpu::BindGroupDescriptor {
+					label: Some("Canvas(Composite Binding group 0)"),
+					layout: &self.pipelines.render[0].get_bind_group_layout(0),
+					entries: &[
+						wgpu::BindGroupEntry {
+							binding: 0,
+							resource: wgpu::BindingResource::TextureView(&layer_view),
+						},
+						wgpu::BindGroupEntry {
+							binding: 1,
+							resource: wgpu::BindingResource::TextureView(&views[current]),
+						},
+					],
+				}
+			);
+
+			let layer_in = LayerInput {
+				opacity: layer.opacity,
+				blend_mode: layer.blend_mode as u32,
+				linear: self.linear_blending as u32,
+			};
+
+			let mut bundle_encoder = ctx.device.create_render_bundle_encoder(&wgpu::RenderBundleEncoderDescriptor {
+				label: Some("Canvas(Composite Bundle Encoder)"),
+				color_formats: &[Some(wgpu::TextureFormat::Rgba8Unorm)],
+				depth_stencil: None,
+				sample_count: 1,
+				multiview: None,
+			});
+			bundle_encoder.set_pipeline(&self.pipelines.render[0]);
+			bundle_encoder.set_bind_group(0, &binding_group, &[]);
+			bundle_encoder.set_push_constants(wgpu::ShaderStages::FRAGMENT, 0, bytemuck::bytes_of(&layer_in));
+			bundle_encoder.draw(0..6, 0..1);
+			bundles.push(bundle_encoder.finish(&wgpu::RenderBundleDescriptor {
+				label: Some("Canvas(Composite Bundle)"),
+			}));
+
+			current = 1 - current;
+		}
+		bundles
+	}
+
+	/// Composites the visible layers, bottom to top over the background color, into `rect` of the image texture.
+	fn composite(&mut self, encoder: &mut wgpu::CommandEncoder, ctx: &Context, rect: Rect) {
+		if self.composite_bundles.is_none() {
+			self.composite_bundles = Some(self.record_composite_bundles(ctx));
+		}
+		let bundles = self.composite_bundles.as_ref().unwrap();
+
+		let targets = [self.image.get_texture().as_ref().unwrap(), &self.composite_scratch];
+		let views = targets.map(|t| t.create_view(&wgpu::TextureViewDescriptor::default()));
+
+		let [r, g, b] = self.backgroud;
+		let background_binding = self.texture_binding(ctx, &views[0]);
+		self.dispatch_clear(encoder, &background_binding, rect, [r, g, b, 1.]);
+
+		let mut current = 0;
+		for bundle in bundles {
+			let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+				label: Some("Canvas(Composite Pass)"),
+				color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+					view: &views[1 - current],
+					resolve_target: None,
+					ops: wgpu::Operations {
+						load: wgpu::LoadOp::Load,
+						store: true,
+					},
+				})],
+				depth_stencil_attachment: None,
+			});
+			render_pass.set_scissor_rect(rect.pos.x as u32, rect.pos.y as u32, rect.size.w, rect.size.h);
+			render_pass.execute_bundles(std::iter::once(bundle));
+			drop(render_pass);
+
+			current = 1 - current;
+		}
+
+		if current == 1 {
+			copy_region(encoder, targets[1], rect.pos, targets[0], rect.pos, rect.size);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Canvas on a headless device. Tests using it are ignored by default, they need an adapter to run.
+	fn headless_canvas(size: Size) -> (Context, Box<Canvas>) {
+		let mut ctx = Context::headless().expect("No adapter supports the canvas");
+		let canvas = Canvas::with_size(&mut ctx, size);
+		(ctx, canvas)
+	}
+
+	/// Renders a frame to a texture the size of the canvas, as the window does.
+	fn render_frame(ctx: &mut Context, canvas: &mut Canvas) {
+		let size = canvas.size();
+		let output = create_texture(ctx, "Canvas(Test Output)", size, wgpu::TextureUsages::RENDER_ATTACHMENT);
+		let view = output.create_view(&wgpu::TextureViewDescriptor::default());
+		let viewport = Rect { pos: Point { x: 0, y: 0 }, size };
+
+		let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+		canvas.render(&mut encoder, ctx, &view, viewport, None);
+		ctx.staging_belt.finish();
+		ctx.queue.submit(std::iter::once(encoder.finish()));
+		ctx.staging_belt.recall();
+	}
+
+	/// Renders frames until nothing is left to draw.
+	fn render_frames(ctx: &mut Context, canvas: &mut Canvas) {
+		for _ in 0..8 {
+			if !canvas.needs_redraw() {
+				return;
+			}
+			render_frame(ctx, canvas);
+		}
+		panic!("Canvas still has something to draw");
+	}
+
+	/// Drags the brush through `points` with full pressure.
+	fn draw(canvas: &mut Canvas, points: &[PointF]) {
+		canvas.mouse_pos(points[0]);
+		canvas.mouse_down();
+		for &p in &points[1..] {
+			canvas.mouse_pos(p);
+		}
+		canvas.mouse_up();
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn export_png_writes_stroke() {
+		// 75 pixels wide, so rows are padded when they're read back
+		let size = Size { w: 75, h: 40 };
+		let (mut ctx, mut canvas) = headless_canvas(size);
+		let (a, b) = (PointF { x: 10., y: 20. }, PointF { x: 60., y: 20. });
+		draw(&mut canvas, &[a, b]);
+		render_frames(&mut ctx, &mut canvas);
+
+		let path = std::env::temp_dir().join(format!("pntr-export-{}.png", std::process::id()));
+		canvas.export_png(&ctx, &path).unwrap();
+		let mut reader = png::Decoder::new(std::fs::File::open(&path).unwrap()).read_info().unwrap();
+		let mut pixels = vec![0; reader.output_buffer_size()];
+		let info = reader.next_frame(&mut pixels).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		assert_eq!((info.width, info.height), (size.w, size.h));
+		assert_eq!(info.color_type, png::ColorType::Rgba);
+		for y in 0..size.h {
+			for x in 0..size.w {
+				let i = 4 * (y * size.w + x) as usize;
+				let center = PointF { x: x as f32 + 0.5, y: y as f32 + 0.5 };
+				let along = center.x.clamp(a.x, b.x);
+				let d = (center.x - along).hypot(center.y - a.y);
+				// The brush color is white over a black background, edges are antialiased
+				if d < BRUSH_RADIUS - 1. {
+					assert_eq!(pixels[i..i + 4], [255; 4], "({x}, {y}) should be painted");
+				} else if d > BRUSH_RADIUS + 1. {
+					assert_eq!(pixels[i..i + 4], [0, 0, 0, 255], "({x}, {y}) should be background");
+				}
+			}
+		}
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn every_point_is_drawn_in_one_frame() {
+		let (mut ctx, mut canvas) = headless_canvas(Size { w: 200, h: 200 });
+		let initial_capacity = canvas.line_buff.capacity;
+
+		// 60 rows of 1000 points, 3 pixels apart
+		const COUNT: usize = 60_000;
+		let points: Vec<PointF> = (0..COUNT)
+			.map(|i| PointF { x: 10. + (i % 1000) as f32 * 0.18, y: 10. + (i / 1000) as f32 * 3. })
+			.collect();
+		draw(&mut canvas, &points);
+		render_frame(&mut ctx, &mut canvas);
+
+		assert!(canvas.line_points.is_empty(), "Points were postponed");
+		assert!(canvas.line_buff.capacity >= (COUNT * std::mem::size_of::<LinePoint>()) as u64);
+		assert!(canvas.line_buff.capacity > initial_capacity);
+
+		let pixels = read_texture(&ctx, &canvas.layers[0].tex, Rect::new(0, 0, 200, 200));
+		for &p in &[points[0], points[COUNT / 2], points[COUNT - 1], points[COUNT / 3 + 500]] {
+			let i = 4 * (p.y as usize * 200 + p.x as usize);
+			assert_eq!(pixels[i + 3], 255, "{p:?} wasn't drawn");
+		}
+	}
+
+	/// Points `spacing` apart along a line, with the same radius.
+	fn line(from: PointF, to: PointF, spacing: f32, radius: f32) -> Vec<LinePoint> {
+		let steps = ((to.x - from.x).hypot(to.y - from.y) / spacing).ceil().max(1.) as usize;
+		(0..=steps)
+			.map(|i| {
+				let t = i as f32 / steps as f32;
+				let pos = PointF { x: from.x + (to.x - from.x) * t, y: from.y + (to.y - from.y) * t };
+				LinePoint { pos, radius, opacity: 1. }
+			})
+			.collect()
+	}
+
+	/// Checks that tiles only hold segments whose bounds, grown by the radius, touch them,
+	/// and that every pixel within the radius of a segment is in a tile holding it.
+	fn assert_binned(points: &[LinePoint], canvas: Size, tiles: &[Tile], segments: &[u32]) {
+		let bounds = |i: usize| {
+			let (a, b) = (points[i], points[i + 1]);
+			let r = a.radius.max(b.radius) + 1.;
+			(a.pos.x.min(b.pos.x) - r, a.pos.y.min(b.pos.y) - r, a.pos.x.max(b.pos.x) + r, a.pos.y.max(b.pos.y) + r)
+		};
+
+		let mut binned = std::collections::HashSet::new();
+		for tile in tiles {
+			let (x, y) = (tile.origin.x, tile.origin.y);
+			assert!(0 <= x && x < canvas.w as i32 && 0 <= y && y < canvas.h as i32, "Tile {x}, {y} is outside the canvas");
+			assert!(binned.insert((x, y, u32::MAX)), "Tile {x}, {y} is binned twice");
+
+			let range = tile.segment_start as usize..(tile.segment_start + tile.segment_count) as usize;
+			for &segment in &segments[range] {
+				let (x0, y0, x1, y1) = bounds(segment as usize);
+				let (tx, ty) = (x as f32, y as f32);
+				let size = TILE_SIZE as f32;
+				assert!(tx < x1 && tx + size > x0 && ty < y1 && ty + size > y0, "Segment {segment} doesn't reach tile {x}, {y}");
+				binned.insert((x, y, segment));
+			}
+		}
+
+		for (i, pair) in points.windows(2).enumerate() {
+			let (a, b) = (pair[0], pair[1]);
+			let (x0, y0, x1, y1) = bounds(i);
+			for y in (y0.max(0.) as i32)..(y1.min(canvas.h as f32) as i32) {
+				for x in (x0.max(0.) as i32)..(x1.min(canvas.w as f32) as i32) {
+					let p = PointF { x: x as f32 + 0.5, y: y as f32 + 0.5 };
+					let (d, len2) = (PointF { x: b.pos.x - a.pos.x, y: b.pos.y - a.pos.y }, (b.pos.x - a.pos.x).powi(2) + (b.pos.y - a.pos.y).powi(2));
+					let t = if len2 == 0. { 0. } else { (((p.x - a.pos.x) * d.x + (p.y - a.pos.y) * d.y) / len2).clamp(0., 1.) };
+					let dist = (p.x - a.pos.x - d.x * t).hypot(p.y - a.pos.y - d.y * t);
+					if dist <= a.radius.max(b.radius) + 0.5 {
+						let tile = (x / TILE_SIZE * TILE_SIZE, y / TILE_SIZE * TILE_SIZE, i as u32);
+						assert!(binned.contains(&tile), "Pixel {x}, {y} of segment {i} isn't in a tile holding it");
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn tiles_cover_segment_bounds() {
+		let canvas = Size { w: 128, h: 128 };
+		let points = line(PointF { x: 20.5, y: 30.5 }, PointF { x: 50.5, y: 35.5 }, 100., 4.);
+		let (mut tiles, mut segments) = (Vec::new(), Vec::new());
+		let area = bin_segments(&points, 0, canvas, &mut tiles, &mut segments);
+
+		// Bounds grown by the radius and a pixel of antialiasing
+		assert_eq!(area, Some(Rect::new(15, 25, 41, 16)));
+		let mut origins: Vec<(i32, i32)> = tiles.iter().map(|t| (t.origin.x, t.origin.y)).collect();
+		origins.sort_unstable();
+		let expected: Vec<(i32, i32)> = (1..=6).flat_map(|x| (3..=5).map(move |y| (x * TILE_SIZE, y * TILE_SIZE))).collect();
+		assert_eq!(origins, expected);
+		assert_binned(&points, canvas, &tiles, &segments);
+	}
+
+	#[test]
+	fn tiles_are_clipped_to_canvas() {
+		let canvas = Size { w: 64, h: 64 };
+		let (mut tiles, mut segments) = (Vec::new(), Vec::new());
+
+		let outside = line(PointF { x: 100., y: 100. }, PointF { x: 120., y: 100. }, 100., 2.);
+		assert_eq!(bin_segments(&outside, 0, canvas, &mut tiles, &mut segments), None);
+		assert!(tiles.is_empty() && segments.is_empty());
+
+		// Indices are offset by the position of the stroke in the line buffer
+		let across = line(PointF { x: -20., y: -20. }, PointF { x: 10., y: 10. }, 100., 2.);
+		let area = bin_segments(&across, 5, canvas, &mut tiles, &mut segments);
+		assert_eq!(area, Some(Rect::new(0, 0, 13, 13)));
+		assert_eq!(tiles.len(), 4);
+		assert!(segments.iter().all(|&s| s == 5));
+	}
+
+	#[test]
+	fn long_diagonal_bins_a_band_of_tiles() {
+		let canvas = Size { w: 1024, h: 1024 };
+		let points = line(PointF { x: 4., y: 4. }, PointF { x: 1020., y: 1020. }, 2., 3.);
+		let (mut tiles, mut segments) = (Vec::new(), Vec::new());
+		let area = bin_segments(&points, 0, canvas, &mut tiles, &mut segments).unwrap();
+		assert_binned(&points, canvas, &tiles, &segments);
+
+		// A few tiles per row instead of the whole bounding box
+		let rows = area.size.h.div_ceil(TILE_SIZE as u32) as usize;
+		let bounding_tiles = rows * area.size.w.div_ceil(TILE_SIZE as u32) as usize;
+		assert!(tiles.len() <= 5 * rows, "{} tiles for {rows} rows", tiles.len());
+		assert!(tiles.len() * 20 < bounding_tiles);
+		// Points are 1.4 pixels apart on each axis, bounds grown by 4 reach a tile from 13 of them
+		assert!(tiles.iter().all(|t| t.segment_count <= 13));
+	}
+
+	/// Compares the tiled line kernel with the one it replaced, which tested every pixel of a stroke's bounds
+	/// against all of its segments. Run with `cargo test --release bench_line_kernels -- --ignored --nocapture`.
+	#[test]
+	#[ignore = "benchmark, needs a Vulkan, Metal or DX12 adapter"]
+	fn bench_line_kernels() {
+		const RUNS: u32 = 20;
+		let size = Size { w: 2048, h: 2048 };
+		let (mut ctx, mut canvas) = headless_canvas(size);
+
+		let shader = ctx.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+			label: Some("Canvas(Line Bundle Bench Shader)"),
+			source: wgpu::ShaderSource::Wgsl(concat!(include_str!("shaders/blend.wgsl"), include_str!("shaders/line_bundle_bench.wgsl")).into()),
+		});
+		let layout = ctx.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+			label: Some("Canvas(Line Bundle Bench Pipeline Layout)"),
+			bind_group_layouts: &[&canvas.pipelines.compute[2].get_bind_group_layout(0), &canvas.pipelines.compute[2].get_bind_group_layout(1)],
+			push_constant_ranges: &[wgpu::PushConstantRange { stages: wgpu::ShaderStages::COMPUTE, range: 0..12*4 }],
+		});
+		let bundle_pipeline = ctx.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+			label: Some("Canvas(Line Bundle Bench Pipeline)"),
+			layout: Some(&layout),
+			module: &shader,
+			entry_point: "draw_line",
+		});
+
+		let strokes = [
+			("short stroke", line(PointF { x: 100., y: 100. }, PointF { x: 200., y: 100. }, 2., BRUSH_RADIUS)),
+			("long diagonal", line(PointF { x: 4., y: 4. }, PointF { x: 2044., y: 2044. }, 2., BRUSH_RADIUS)),
+		];
+
+		for (name, points) in strokes {
+			canvas.line_points.push_back(Stroke {
+				points: VecDeque::new(),
+				recorded: Vec::new(),
+				layer: canvas.layers[0].id,
+				color: [1., 1., 1.],
+				opacity: 1.,
+				blend_mode: BlendMode::Normal,
+				linear: false,
+				erase: false,
+			});
+
+			let submit = |ctx: &mut Context, canvas: &mut Canvas, bundle: bool| {
+				let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+				let (mut tiles, mut segments) = (Vec::new(), Vec::new());
+				// The old kernel covered the same area, without the tiles
+				let area = bin_segments(&points, 0, size, &mut tiles, &mut segments).unwrap();
+				canvas.upload_lines(&mut encoder, ctx, &points, &tiles, &segments);
+
+				let mask_view = canvas.mask.create_view(&wgpu::TextureViewDescriptor::default());
+				canvas.dispatch_clear(&mut encoder, &canvas.texture_binding(ctx, &mask_view), area, [0.; 4]);
+
+				if bundle {
+					let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+					compute_pass.set_pipeline(&bundle_pipeline);
+					compute_pass.set_bind_group(0, &canvas.stroke_bindings[&canvas.layers[0].id], &[]);
+					compute_pass.set_bind_group(1, &canvas.line_binding, &[]);
+					compute_pass.set_push_constants(0, bytemuck::bytes_of(&area.pos));
+					compute_pass.set_push_constants(8, bytemuck::cast_slice(&[0, points.len() as u32]));
+					compute_pass.set_push_constants(4*4, bytemuck::cast_slice(&[1f32; 4]));
+					compute_pass.set_push_constants(4*8, bytemuck::cast_slice(&[canvas.antialias as u32, 0, 0, 0]));
+					compute_pass.dispatch_workgroups(area.size.w / 8 + 1, area.size.h / 8 + 1, 1);
+				} else {
+					let batch = StrokeBatch { area: Some(area), points: points.len(), tiles: 0..tiles.len() as u32 };
+					canvas.dispatch_stroke(&mut encoder, ctx, 0, &batch);
+				}
+
+				ctx.staging_belt.finish();
+				ctx.queue.submit(std::iter::once(encoder.finish()));
+				ctx.staging_belt.recall();
+				ctx.device.poll(wgpu::Maintain::Wait);
+			};
+
+			// The tiled kernel runs first so the stroke binding is cached for the old one
+			let mut timings = [("tiled", false), ("per-bundle", true)].map(|(kernel, bundle)| {
+				submit(&mut ctx, &mut canvas, bundle);
+				let start = std::time::Instant::now();
+				for _ in 0..RUNS {
+					submit(&mut ctx, &mut canvas, bundle);
+				}
+				(kernel, start.elapsed() / RUNS)
+			});
+			timings.sort_by_key(|(kernel, _)| *kernel != "per-bundle");
+			let [(_, before), (_, after)] = timings;
+			println!("{name}, {} points: per-bundle {before:?}, tiled {after:?} ({:.1}x)", points.len(), before.as_secs_f64() / after.as_secs_f64());
+
+			canvas.line_points.clear();
+		}
+	}
+
+	/// Time `Canvas::render` takes for a frame of a canvas with 8 layers, compositing only the damaged area or all of it,
+	/// and apart from that the time to create the bind groups and bundles that are cached.
+	/// Run with `cargo test --release bench_canvas_render -- --ignored --nocapture`.
+	#[test]
+	#[ignore = "benchmark, needs a Vulkan, Metal or DX12 adapter"]
+	fn bench_canvas_render() {
+		const FRAMES: u32 = 200;
+		let size = Size { w: 2048, h: 2048 };
+		let (mut ctx, mut canvas) = headless_canvas(size);
+		for _ in 1..8 {
+			canvas.add_layer(&ctx);
+		}
+		let output = create_texture(&ctx, "Canvas(Test Output)", size, wgpu::TextureUsages::RENDER_ATTACHMENT);
+		let view = output.create_view(&wgpu::TextureViewDescriptor::default());
+		let viewport = Rect { pos: Point { x: 0, y: 0 }, size };
+
+		// Encoding, and encoding with the GPU work waited for
+		let frame = |ctx: &mut Context, canvas: &mut Canvas| {
+			let start = std::time::Instant::now();
+			let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+			canvas.render(&mut encoder, ctx, &view, viewport, None);
+			let commands = encoder.finish();
+			let encoded = start.elapsed();
+
+			ctx.staging_belt.finish();
+			ctx.queue.submit(std::iter::once(commands));
+			ctx.staging_belt.recall();
+			ctx.device.poll(wgpu::Maintain::Wait);
+			(encoded, start.elapsed())
+		};
+
+		// Composite time alone, the bundles and bind groups are kept
+		for stroke in [false, true] {
+			for damaged in [false, true] {
+				frame(&mut ctx, &mut canvas);
+				let (mut encoding, mut total) = (std::time::Duration::ZERO, std::time::Duration::ZERO);
+				for i in 0..FRAMES {
+					if stroke {
+						let y = 100. + (i % 1000) as f32 * 1.5;
+						draw(&mut canvas, &[PointF { x: 100., y }, PointF { x: 300., y }]);
+					}
+					if damaged {
+						canvas.mark_damaged(viewport);
+					}
+					let (encoded, elapsed) = frame(&mut ctx, &mut canvas);
+					encoding += encoded;
+					total += elapsed;
+				}
+
+				let scene = if stroke { "drawing a stroke" } else { "idle" };
+				let composite = if damaged { "full composite" } else { "damaged area only" };
+				println!("{scene}, {composite}: {:?} encoding, {:?} with the GPU per frame", encoding / FRAMES, total / FRAMES);
+			}
+		}
+
+		// What caching saves, timed on its own
+		let start = std::time::Instant::now();
+		for _ in 0..FRAMES {
+			for layer in &canvas.layers {
+				canvas.stroke_binding(&ctx, layer);
+			}
+		}
+		println!("stroke bind group: {:?} per layer", start.elapsed() / (FRAMES * canvas.layers.len() as u32));
+
+		let start = std::time::Instant::now();
+		for _ in 0..FRAMES {
+			canvas.record_composite_bundles(&ctx);
+		}
+		println!("composite bundles of {} layers: {:?}", canvas.layers.len(), start.elapsed() / FRAMES);
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn undo_survives_resize() {
+		let (mut ctx, mut canvas) = headless_canvas(Size { w: 40, h: 40 });
+		draw(&mut canvas, &[PointF { x: 10., y: 20. }, PointF { x: 30., y: 20. }]);
+		render_frames(&mut ctx, &mut canvas);
+
+		// Grown by 20 pixels on each side, the stroke moves with the content
+		let size = Size { w: 80, h: 80 };
+		canvas.resize(&ctx, size, Anchor { x: 0.5, y: 0.5 });
+		let full = Rect { pos: Point { x: 0, y: 0 }, size };
+		let pixels = read_texture(&ctx, &canvas.layers[0].tex, full);
+		let i = 4 * (40 * 80 + 40);
+		assert_eq!(pixels[i + 3], 255);
+
+		canvas.undo();
+		render_frames(&mut ctx, &mut canvas);
+		let pixels = read_texture(&ctx, &canvas.layers[0].tex, full);
+		assert!(pixels.iter().all(|&b| b == 0));
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn undo_after_load_does_nothing() {
+		let (mut ctx, mut canvas) = headless_canvas(Size { w: 40, h: 40 });
+		draw(&mut canvas, &[PointF { x: 10., y: 10. }, PointF { x: 30., y: 10. }]);
+		render_frames(&mut ctx, &mut canvas);
+		let path = std::env::temp_dir().join(format!("pntr-undo-load-{}.pntr", std::process::id()));
+		canvas.save_project(&ctx, &path).unwrap();
+
+		// History of a different document, with a layer of the same id
+		draw(&mut canvas, &[PointF { x: 10., y: 30. }, PointF { x: 30., y: 30. }]);
+		render_frames(&mut ctx, &mut canvas);
+		canvas.load_project(&ctx, &path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		render_frames(&mut ctx, &mut canvas);
+
+		let full = Rect::new(0, 0, 40, 40);
+		let loaded = read_texture(&ctx, &canvas.layers[0].tex, full);
+		canvas.undo();
+		canvas.undo();
+		render_frames(&mut ctx, &mut canvas);
+		assert!(read_texture(&ctx, &canvas.layers[0].tex, full) == loaded, "Undo changed the loaded layer");
+	}
+
+	fn srgb_to_linear(c: f32) -> f32 {
+		if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+	}
+
+	fn linear_to_srgb(c: f32) -> f32 {
+		if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1. / 2.4) - 0.055 }
+	}
+
+	/// Reference for `blend` in `blend.wgsl`.
+	fn blend(mode: BlendMode, b: f32, s: f32) -> f32 {
+		match mode {
+			BlendMode::Normal => s,
+			BlendMode::Multiply => b * s,
+			BlendMode::Screen => b + s - b * s,
+			BlendMode::Overlay if b <= 0.5 => 2. * b * s,
+			BlendMode::Overlay => 1. - 2. * (1. - b) * (1. - s),
+			BlendMode::Darken => b.min(s),
+			BlendMode::Lighten => b.max(s),
+		}
+	}
+
+	/// Checks a pixel of a stroke of `source` with `mode` over an opaque `backdrop` against the reference.
+	fn assert_blended(pixel: &[u8], mode: BlendMode, linear: bool, backdrop: [f32; 3], source: [f32; 3]) {
+		for c in 0..3 {
+			let expected = if linear {
+				linear_to_srgb(blend(mode, srgb_to_linear(backdrop[c]), srgb_to_linear(source[c])))
+			} else {
+				blend(mode, backdrop[c], source[c])
+			};
+			let expected = (expected * 255.).round() as i32;
+			assert!((pixel[c] as i32 - expected).abs() <= 1, "{mode:?} linear: {linear}, channel {c}: {} != {expected}", pixel[c]);
+		}
+		assert_eq!(pixel[3], 255);
+	}
+
+	// Backdrop channels on both sides of 0.5 for overlay, they're exact in Rgba8
+	const BACKDROP: [f32; 3] = [51. / 255., 153. / 255., 204. / 255.];
+	const SOURCE: [f32; 3] = [0.7, 0.3, 0.5];
+
+	/// Fills the canvas with an opaque backdrop.
+	fn fill_backdrop(ctx: &mut Context, canvas: &mut Canvas) {
+		canvas.mouse_pos(PointF { x: 0.5, y: 0.5 });
+		canvas.set_brush_color(BACKDROP);
+		canvas.fill(ctx, FillMode::Global);
+		render_frames(ctx, canvas);
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn filled_shape_waits_for_pending_strokes() {
+		let (mut ctx, mut canvas) = headless_canvas(Size { w: 16, h: 16 });
+		draw(&mut canvas, &[PointF { x: 2., y: 8. }, PointF { x: 14., y: 8. }]);
+		canvas.shape_kind = Some(ShapeKind::Rectangle);
+		canvas.fill_shapes = true;
+		canvas.set_brush_color([1., 0., 0.]);
+		draw(&mut canvas, &[PointF { x: 1., y: 1. }, PointF { x: 15., y: 15. }]);
+
+		render_frame(&mut ctx, &mut canvas);
+		assert!(canvas.shape.is_some(), "Layer read back before the stroke was submitted");
+
+		render_frames(&mut ctx, &mut canvas);
+		assert!(canvas.shape.is_none());
+		// The rectangle covers the stroke instead of being overwritten by it
+		let pixel = read_texture(&ctx, &canvas.layers[0].tex, Rect::new(8, 8, 1, 1));
+		assert_eq!(pixel, [255, 0, 0, 255]);
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn delete_waits_for_pending_strokes() {
+		let (mut ctx, mut canvas) = headless_canvas(Size { w: 16, h: 16 });
+		draw(&mut canvas, &[PointF { x: 2., y: 8. }, PointF { x: 14., y: 8. }]);
+		canvas.select_kind = Some(SelectionKind::Rectangle);
+		draw(&mut canvas, &[PointF { x: 0., y: 0. }, PointF { x: 16., y: 16. }]);
+		canvas.delete_selection(&ctx);
+		assert!(canvas.fill.is_none(), "Layer read back before the stroke was drawn");
+
+		render_frames(&mut ctx, &mut canvas);
+		canvas.delete_selection(&ctx);
+		render_frames(&mut ctx, &mut canvas);
+		let pixel = read_texture(&ctx, &canvas.layers[0].tex, Rect::new(8, 8, 1, 1));
+		assert_eq!(pixel, [0; 4]);
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn fill_waits_for_pending_strokes() {
+		let (mut ctx, mut canvas) = headless_canvas(Size { w: 16, h: 16 });
+		draw(&mut canvas, &[PointF { x: 2., y: 8. }, PointF { x: 14., y: 8. }]);
+		canvas.mouse_pos(PointF { x: 0.5, y: 0.5 });
+		canvas.fill(&ctx, FillMode::Global);
+		assert!(canvas.fill.is_none(), "Layer read back before the stroke was drawn");
+
+		render_frames(&mut ctx, &mut canvas);
+		canvas.fill(&ctx, FillMode::Global);
+		assert!(canvas.fill.is_some());
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn blend_modes_match_reference() {
+		let modes = [BlendMode::Normal, BlendMode::Multiply, BlendMode::Screen, BlendMode::Overlay, BlendMode::Darken, BlendMode::Lighten];
+
+		for mode in modes {
+			for linear in [false, true] {
+				let (mut ctx, mut canvas) = headless_canvas(Size { w: 16, h: 16 });
+				fill_backdrop(&mut ctx, &mut canvas);
+
+				// A fully covering stroke over the backdrop
+				canvas.set_blend_mode(mode);
+				if linear {
+					canvas.toggle_linear_blending();
+				}
+				canvas.set_brush_color(SOURCE);
+				draw(&mut canvas, &[PointF { x: 4., y: 8.5 }, PointF { x: 12., y: 8.5 }]);
+				render_frames(&mut ctx, &mut canvas);
+
+				let pixel = read_texture(&ctx, &canvas.layers[0].tex, Rect::new(8, 8, 1, 1));
+				assert_blended(&pixel, mode, linear, BACKDROP, SOURCE);
+			}
+		}
+	}
+
+	#[test]
+	#[ignore = "needs a Vulkan, Metal or DX12 adapter"]
+	fn linear_blending_is_fixed_per_stroke() {
+		let (mut ctx, mut canvas) = headless_canvas(Size { w: 16, h: 16 });
+		fill_backdrop(&mut ctx, &mut canvas);
+		canvas.set_blend_mode(BlendMode::Multiply);
+		canvas.set_brush_color(SOURCE);
+
+		// Toggled while the first stroke is drawn, it only applies to the second one
+		canvas.mouse_pos(PointF { x: 4., y: 4.5 });
+		canvas.mouse_down();
+		canvas.toggle_linear_blending();
+		canvas.mouse_pos(PointF { x: 12., y: 4.5 });
+		canvas.mouse_up();
+		assert!(canvas.line_points.iter().all(|s| !s.linear));
+		draw(&mut canvas, &[PointF { x: 4., y: 11.5 }, PointF { x: 12., y: 11.5 }]);
+		render_frames(&mut ctx, &mut canvas);
+
+		let pixels = read_texture(&ctx, &canvas.layers[0].tex, Rect::new(8, 0, 1, 16));
+		assert_blended(&pixels[4 * 4..4 * 5], BlendMode::Multiply, false, BACKDROP, SOURCE);
+		assert_blended(&pixels[4 * 11..4 * 12], BlendMode::Multiply, true, BACKDROP, SOURCE);
+	}
+
+	#[test]
+	fn letterbox_centers_and_fits() {
+		let canvas = Size { w: 200, h: 100 };
+		let small = letterbox(Size { w: 50, h: 20 }, canvas);
+		assert_eq!((small.pos.x, small.pos.y, small.size.w, small.size.h), (75, 40, 50, 20));
+
+		// Wider than the canvas, scaled down to its width
+		let wide = letterbox(Size { w: 400, h: 100 }, canvas);
+		assert_eq!((wide.pos.x, wide.pos.y, wide.size.w, wide.size.h), (0, 25, 200, 50));
+
+		// Taller, scaled down to its height
+		let tall = letterbox(Size { w: 100, h: 400 }, canvas);
+		assert_eq!((tall.pos.x, tall.pos.y, tall.size.w, tall.size.h), (87, 0, 25, 100));
+	}
+
+	#[test]
+	fn row_padding_is_stripped() {
+		// 75 Rgba8 pixels are 300 bytes, padded to 512
+		let (row_bytes, padded_row_bytes) = (300, 512);
+		let mut padded = vec![0; 3 * padded_row_bytes];
+		for (row, bytes) in padded.chunks_mut(padded_row_bytes).enumerate() {
+			bytes[..row_bytes].fill(row as u8 + 1);
+		}
+		let pixels = strip_row_padding(&padded, row_bytes, padded_row_bytes);
+		assert_eq!(pixels.len(), 3 * row_bytes);
+		for (row, bytes) in pixels.chunks(row_bytes).enumerate() {
+			assert!(bytes.iter().all(|&b| b == row as u8 + 1));
+		}
+	}
+
+	#[test]
+	fn pressure_curve_endpoints() {
+		let curve = PressureCurve::default();
+		assert_eq!(curve.map(0.), (curve.min_radius, curve.min_opacity));
+		assert_eq!(curve.map(1.), (1., 1.));
+	}
+
+	#[test]
+	fn pressure_curve_is_monotonic() {
+		let curve = PressureCurve::default();
+		let mapped: Vec<(f32, f32)> = (0..=100).map(|i| curve.map(i as f32 / 100.)).collect();
+		assert!(mapped.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+	}
+
+	#[test]
+	fn pressure_curve_clamps() {
+		let curve = PressureCurve::default();
+		assert_eq!(curve.map(-1.), curve.map(0.));
+		assert_eq!(curve.map(2.), curve.map(1.));
+		assert_eq!(curve.map(f32::INFINITY), (1., 1.));
+	}
 }
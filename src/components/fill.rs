@@ -0,0 +1,214 @@
+//! Bucket fill, computed on the CPU from a readback of the layer.
+
+use crate::components::{Point, Rect, Size};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FillMode {
+	/// Only the area connected to the seed through pixels sharing an edge, diagonal gaps don't leak.
+	Contiguous,
+	/// Every similar pixel of the layer.
+	Global,
+}
+
+/// Pixels to fill in `pixels`, Rgba8 rows of `size`: those whose channels all differ by at most `tolerance`
+/// from the ones at `seed`. Returns a mask of the whole layer and the area it covers.
+pub fn fill_mask(pixels: &[u8], size: Size, seed: Point, tolerance: u8, mode: FillMode) -> Option<(Vec<bool>, Rect)> {
+	let (w, h) = (size.w as usize, size.h as usize);
+	if seed.x < 0 || seed.y < 0 || seed.x as usize >= w || seed.y as usize >= h {
+		return None;
+	}
+
+	let seed_index = seed.y as usize * w + seed.x as usize;
+	let target = &pixels[4 * seed_index..4 * seed_index + 4];
+	let similar = |i: usize| pixels[4 * i..4 * i + 4].iter().zip(target).all(|(p, t)| p.abs_diff(*t) <= tolerance);
+
+	let mut mask = vec![false; w * h];
+	match mode {
+		FillMode::Global => {
+			for (i, m) in mask.iter_mut().enumerate() {
+				*m = similar(i);
+			}
+		}
+		FillMode::Contiguous => {
+			// Scanline fill: each seed grows into a whole run of its row, runs above and below it are seeded next
+			let mut seeds = vec![(seed.x as usize, seed.y as usize)];
+			while let Some((x, y)) = seeds.pop() {
+				let row = y * w;
+				if mask[row + x] {
+					continue;
+				}
+
+				let fillable = |i: usize, mask: &[bool]| !mask[i] && similar(i);
+				let mut left = x;
+				while left > 0 && fillable(row + left - 1, &mask) {
+					left -= 1;
+				}
+				let mut right = x;
+				while right + 1 < w && fillable(row + right + 1, &mask) {
+					right += 1;
+				}
+				mask[row + left..=row + right].fill(true);
+
+				for ny in [y.checked_sub(1), Some(y + 1).filter(|&ny| ny < h)].into_iter().flatten() {
+					let mut in_run = false;
+					for nx in left..=right {
+						let fill = fillable(ny * w + nx, &mask);
+						if fill && !in_run {
+							seeds.push((nx, ny));
+						}
+						in_run = fill;
+					}
+				}
+			}
+		}
+	}
+
+	let mut min = (w, h);
+	let mut max = (0, 0);
+	for (i, _) in mask.iter().enumerate().filter(|(_, m)| **m) {
+		let (x, y) = (i % w, i / w);
+		min = (min.0.min(x), min.1.min(y));
+		max = (max.0.max(x), max.1.max(y));
+	}
+
+	let rect = Rect {
+		pos: Point { x: min.0 as i32, y: min.1 as i32 },
+		size: Size { w: (max.0 + 1 - min.0) as u32, h: (max.1 + 1 - min.1) as u32 },
+	};
+	Some((mask, rect))
+}
+
+/// Paints `color` with `opacity` over the pixels of `rect`, scaled by how much of each one is covered,
+/// and returns them as Rgba8 rows. `pixels` are Rgba8 rows of `size`, `coverage` gets indices into them.
+/// Blending is "source over" on the sRGB encoded values.
+pub fn fill_region(pixels: &[u8], size: Size, rect: Rect, color: [f32; 3], opacity: f32, coverage: impl Fn(usize) -> f32) -> Vec<u8> {
+	let w = size.w as usize;
+	let mut region = Vec::with_capacity(4 * (rect.size.w * rect.size.h) as usize);
+	for y in rect.pos.y as usize..rect.pos.y as usize + rect.size.h as usize {
+		for x in rect.pos.x as usize..rect.pos.x as usize + rect.size.w as usize {
+			let i = y * w + x;
+			let dst = &pixels[4 * i..4 * i + 4];
+			match coverage(i).min(1.) {
+				c if c > 0. => region.extend_from_slice(&source_over(dst, color, opacity * c)),
+				_ => region.extend_from_slice(dst),
+			}
+		}
+	}
+	region
+}
+
+/// Non premultiplied "source over", like the one of the shaders.
+pub fn source_over(dst: &[u8], src: [f32; 3], src_alpha: f32) -> [u8; 4] {
+	let dst_alpha = dst[3] as f32 / 255.;
+	let alpha = src_alpha + dst_alpha * (1. - src_alpha);
+	if alpha == 0. {
+		return [0; 4];
+	}
+
+	let channel = |i: usize| {
+		let c = (src[i] * src_alpha + dst[i] as f32 / 255. * dst_alpha * (1. - src_alpha)) / alpha;
+		(c.clamp(0., 1.) * 255.).round() as u8
+	};
+	[channel(0), channel(1), channel(2), (alpha * 255.).round() as u8]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BLACK: [u8; 4] = [0, 0, 0, 255];
+	const WHITE: [u8; 4] = [255; 4];
+
+	/// Rgba8 rows from a picture where `#` is black and anything else is white.
+	fn picture(rows: &[&str]) -> (Vec<u8>, Size) {
+		let size = Size { w: rows[0].len() as u32, h: rows.len() as u32 };
+		let pixels = rows.iter()
+			.flat_map(|row| row.chars())
+			.flat_map(|c| if c == '#' { BLACK } else { WHITE })
+			.collect();
+		(pixels, size)
+	}
+
+	/// Mask as a picture where `*` is filled.
+	fn filled(mask: &[bool], size: Size) -> Vec<String> {
+		mask.chunks(size.w as usize)
+			.map(|row| row.iter().map(|&m| if m { '*' } else { '.' }).collect())
+			.collect()
+	}
+
+	#[test]
+	fn diagonal_gap_does_not_leak() {
+		// The ring is closed only by pixels touching at a corner
+		let (pixels, size) = picture(&[
+			"........",
+			"..###...",
+			".#...#..",
+			".#...#..",
+			"..#.#...",
+			"...#....",
+			"........",
+		]);
+		let (mask, rect) = fill_mask(&pixels, size, Point { x: 3, y: 3 }, 0, FillMode::Contiguous).unwrap();
+		assert_eq!(filled(&mask, size), [
+			"........",
+			"........",
+			"..***...",
+			"..***...",
+			"...*....",
+			"........",
+			"........",
+		]);
+		assert_eq!(rect, Rect::new(2, 2, 3, 3));
+	}
+
+	#[test]
+	fn global_fill_picks_up_disconnected_regions() {
+		let (pixels, size) = picture(&[
+			"..#..",
+			"###..",
+			"...##",
+			"#.#..",
+		]);
+		let (mask, rect) = fill_mask(&pixels, size, Point { x: 0, y: 0 }, 0, FillMode::Global).unwrap();
+		assert_eq!(filled(&mask, size), [
+			"**.**",
+			"...**",
+			"***..",
+			".*.**",
+		]);
+		assert_eq!(rect, Rect::new(0, 0, 5, 4));
+
+		let (mask, _) = fill_mask(&pixels, size, Point { x: 0, y: 0 }, 0, FillMode::Contiguous).unwrap();
+		assert_eq!(filled(&mask, size), [
+			"**...",
+			".....",
+			".....",
+			".....",
+		]);
+	}
+
+	#[test]
+	fn tolerance_is_inclusive() {
+		// A gradient of gray, one channel a step of 10 from the next
+		let size = Size { w: 6, h: 1 };
+		let pixels: Vec<u8> = (0..6).flat_map(|i| [100, 100, 100 + 10 * i, 255]).collect();
+		let seed = Point { x: 0, y: 0 };
+
+		for (tolerance, count) in [(0, 1), (9, 1), (10, 2), (29, 3), (30, 4), (255, 6)] {
+			for mode in [FillMode::Contiguous, FillMode::Global] {
+				let (mask, rect) = fill_mask(&pixels, size, seed, tolerance, mode).unwrap();
+				assert_eq!(mask.iter().filter(|&&m| m).count(), count, "tolerance {tolerance}, {mode:?}");
+				assert_eq!(rect, Rect::new(0, 0, count as u32, 1));
+			}
+		}
+	}
+
+	#[test]
+	fn seed_outside_is_ignored() {
+		let (pixels, size) = picture(&["...", "..."]);
+		for seed in [Point { x: -1, y: 0 }, Point { x: 0, y: -1 }, Point { x: 3, y: 0 }, Point { x: 0, y: 2 }] {
+			assert!(fill_mask(&pixels, size, seed, 0, FillMode::Contiguous).is_none(), "{seed:?}");
+			assert!(fill_mask(&pixels, size, seed, 0, FillMode::Global).is_none(), "{seed:?}");
+		}
+	}
+}
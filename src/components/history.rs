@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+
+use crate::components::{Point, Rect, Size};
+
+/// Maximum amount of texture memory (in bytes) kept alive by the undo history.
+/// When exceeded the oldest snapshots are dropped.
+const HISTORY_BUDGET: u64 = 256 * 1024 * 1024;
+
+/// Region of a layer as it was before and after an operation.
+/// Both textures have exactly the size of `rect`.
+pub struct Snapshot {
+	pub layer: u32,
+	pub rect: Rect,
+	/// Part of `rect` inside the canvas, it shrinks when the canvas is resized. `None` once it's cut off entirely
+	pub clip: Option<Rect>,
+	pub before: wgpu::Texture,
+	pub after: wgpu::Texture,
+	/// Length of the stroke log before and after the operation
+	pub log: (usize, usize),
+}
+
+impl Snapshot {
+	fn bytes(&self) -> u64 {
+		// Two Rgba8 textures
+		2 * 4 * self.rect.size.w as u64 * self.rect.size.h as u64
+	}
+}
+
+#[derive(Default)]
+pub struct History {
+	undo: VecDeque<Snapshot>,
+	redo: Vec<Snapshot>,
+	bytes: u64,
+}
+
+impl History {
+	/// Records a new operation, invalidating everything that could be redone.
+	pub fn push(&mut self, snapshot: Snapshot) {
+		self.bytes += snapshot.bytes();
+		self.undo.push_back(snapshot);
+
+		for s in self.redo.drain(..) {
+			self.bytes -= s.bytes();
+		}
+
+		while self.bytes > HISTORY_BUDGET && self.undo.len() > 1 {
+			let s = self.undo.pop_front().unwrap();
+			self.bytes -= s.bytes();
+		}
+	}
+
+	/// Moves the last operation to the redo stack and returns it, its `before` texture should be restored.
+	pub fn undo(&mut self) -> Option<&Snapshot> {
+		let s = self.undo.pop_back()?;
+		self.redo.push(s);
+		self.redo.last()
+	}
+
+	/// Moves the last undone operation back to the undo stack and returns it, its `after` texture should be restored.
+	pub fn redo(&mut self) -> Option<&Snapshot> {
+		let s = self.redo.pop()?;
+		self.undo.push_back(s);
+		self.undo.back()
+	}
+
+	/// Moves the snapshots with content that was moved by `offset` when the canvas was resized to `canvas`.
+	/// What could be redone is dropped, like the stroke log does.
+	pub fn rebase(&mut self, offset: Point, canvas: Size) {
+		for s in self.redo.drain(..) {
+			self.bytes -= s.bytes();
+		}
+
+		let full = Rect { pos: Point { x: 0, y: 0 }, size: canvas };
+		for s in self.undo.iter_mut() {
+			s.rect += offset;
+			s.clip = s.clip.and_then(|c| (c + offset).intersection(full));
+		}
+	}
+}
@@ -1,9 +1,48 @@
-use crate::components::{self, Rect, Context, Pipelines, RectViewportClipSpace};
+use bytemuck::{Pod, Zeroable};
+
+use crate::components::{self, PointF, Rect, Context, Pipelines, RectViewportClipSpace};
+
+/// Placement of the texture inside the viewport: texel `t` is drawn at `offset + t * scale`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ViewTransform {
+	pub offset: [f32; 2],
+	pub scale: f32,
+}
+
+impl Default for ViewTransform {
+	fn default() -> Self {
+		ViewTransform {
+			offset: [0., 0.],
+			scale: 1.,
+		}
+	}
+}
+
+impl ViewTransform {
+	/// Converts a position relative to the viewport into texture coordinates, texel `t` spans `t..t+1`.
+	pub fn texture_pos(&self, p: PointF) -> PointF {
+		PointF {
+			x: (p.x - self.offset[0]) / self.scale,
+			y: (p.y - self.offset[1]) / self.scale,
+		}
+	}
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+struct ViewInput {
+	origin: [f32; 2],
+	scale: f32,
+	_padding: u32,
+}
 
 pub struct Image {
 	pipelines: std::sync::Arc<Pipelines>,
 	tex: Option<wgpu::Texture>,
 	binding_group: Option<wgpu::BindGroup>,
+	view: ViewTransform,
+	/// Draw commands for the current texture and view input, recorded again when either changes
+	bundle: Option<(ViewInput, wgpu::RenderBundle)>,
 }
 
 impl components::Component for Image {
@@ -33,7 +72,12 @@ impl components::Component for Image {
 				label: Some("Image(Pipeline Layout)"),
 				bind_group_layouts: &[&binding_group_layout],
 
-				push_constant_ranges: &[],
+				push_constant_ranges: &[
+					wgpu::PushConstantRange {
+						stages: wgpu::ShaderStages::FRAGMENT,
+						range: (0..std::mem::size_of::<ViewInput>() as u32),
+					}
+				],
 			}
 		);
 
@@ -87,11 +131,40 @@ impl components::Component for Image {
 			pipelines: ctx.get_pipelines::<Self>(),
 			tex: None,
 			binding_group: None,
+			view: ViewTransform::default(),
+			bundle: None,
 		})
 
 	}
 
-	fn render(&mut self, encoder: &mut wgpu::CommandEncoder, _: &mut Context, output: & wgpu::TextureView, viewport: Rect, clip_space: Option<Rect>) {
+	fn render(&mut self, encoder: &mut wgpu::CommandEncoder, ctx: &mut Context, output: & wgpu::TextureView, viewport: Rect, clip_space: Option<Rect>) {
+		let view = ViewInput {
+			origin: [viewport.pos.x as f32 + self.view.offset[0], viewport.pos.y as f32 + self.view.offset[1]],
+			scale: self.view.scale,
+			_padding: 0,
+		};
+
+		if !self.bundle.as_ref().is_some_and(|(v, _)| *v == view) {
+			let mut bundle_encoder = ctx.device.create_render_bundle_encoder(&wgpu::RenderBundleEncoderDescriptor {
+				label: Some("Image(Render Bundle Encoder)"),
+				color_formats: &[Some(ctx.surface_format)],
+				depth_stencil: None,
+				sample_count: 1,
+				multiview: None,
+			});
+
+			bundle_encoder.set_pipeline(&self.pipelines.render[0]);
+			let binding = self.binding_group.as_ref().expect("Trying to render Image with no texture");
+			bundle_encoder.set_bind_group(0, binding, &[]);
+			bundle_encoder.set_push_constants(wgpu::ShaderStages::FRAGMENT, 0, bytemuck::bytes_of(&view));
+			bundle_encoder.draw(0..6, 0..1);
+
+			let bundle = bundle_encoder.finish(&wgpu::RenderBundleDescriptor {
+				label: Some("Image(Render Bundle)"),
+			});
+			self.bundle = Some((view, bundle));
+		}
+
 		let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
 			label: Some("Image(Render Pass)"),
 			color_attachments: &[Some(wgpu::RenderPassColorAttachment {
@@ -105,12 +178,10 @@ impl components::Component for Image {
 			depth_stencil_attachment: None,
 		});
 
-		render_pass.set_pipeline(&self.pipelines.render[0]);
+		// Viewport and scissor aren't part of render bundles
 		render_pass.set_viewport_rect(viewport);
 		render_pass.set_clipspace_rect(clip_space);
-		let binding = self.binding_group.as_ref().expect("Trying to render Image with no texture");
-		render_pass.set_bind_group(0, &binding, &[]);
-		render_pass.draw(0..6, 0..1);
+		render_pass.execute_bundles(self.bundle.iter().map(|(_, b)| b));
 
 		drop(render_pass)
 	}
@@ -126,6 +197,10 @@ impl Image {
 		&self.tex
 	}
 
+	pub fn set_view(&mut self, view: ViewTransform) {
+		self.view = view;
+	}
+
 	pub fn set_texture(&mut self, ctx: &Context, tex: wgpu::Texture) {
 		self.tex = Some(tex);
 
@@ -148,5 +223,79 @@ impl Image {
 		);
 
 		self.binding_group = Some(binding_group);
+		self.bundle = None;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::components::{Component, Size};
+
+	/// Time `Image::render` takes to encode a frame, executing the recorded bundle, and encoding the draw directly
+	/// as before it was recorded. Run with `cargo test --release bench_image_render -- --ignored --nocapture`.
+	#[test]
+	#[ignore = "benchmark, needs a Vulkan, Metal or DX12 adapter"]
+	fn bench_image_render() {
+		const FRAMES: u32 = 1000;
+		let mut ctx = Context::headless().expect("No adapter supports the image");
+
+		let size = Size { w: 1024, h: 1024 };
+		let texture = |usage| ctx.device.create_texture(&wgpu::TextureDescriptor {
+			label: Some("Image(Test Texture)"),
+			size: size.into(),
+			mip_level_count: 1,
+			sample_count: 1,
+			dimension: wgpu::TextureDimension::D2,
+			format: wgpu::TextureFormat::Rgba8Unorm,
+			usage,
+		});
+		let tex = texture(wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING);
+		let output = texture(wgpu::TextureUsages::RENDER_ATTACHMENT);
+		let output = output.create_view(&wgpu::TextureViewDescriptor::default());
+		let viewport = Rect::new(0, 0, size.w, size.h);
+
+		let mut image = Image::new(&mut ctx);
+		image.set_texture(&ctx, tex);
+
+		for bundled in [false, true] {
+			let mut total = std::time::Duration::ZERO;
+			for _ in 0..FRAMES {
+				let start = std::time::Instant::now();
+				let mut encoder = ctx.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+				if bundled {
+					image.render(&mut encoder, &mut ctx, &output, viewport, Some(viewport));
+				} else {
+					let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+						label: Some("Image(Render Pass)"),
+						color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+							view: &output,
+							resolve_target: None,
+							ops: wgpu::Operations {
+								load: wgpu::LoadOp::Load,
+								store: true,
+							}
+						})],
+						depth_stencil_attachment: None,
+					});
+					render_pass.set_pipeline(&image.pipelines.render[0]);
+					render_pass.set_viewport_rect(viewport);
+					render_pass.set_clipspace_rect(Some(viewport));
+					render_pass.set_bind_group(0, image.binding_group.as_ref().unwrap(), &[]);
+					let view = ViewInput { origin: [0., 0.], scale: 1., _padding: 0 };
+					render_pass.set_push_constants(wgpu::ShaderStages::FRAGMENT, 0, bytemuck::bytes_of(&view));
+					render_pass.draw(0..6, 0..1);
+				}
+				let commands = encoder.finish();
+				total += start.elapsed();
+
+				// Only encoding is timed, the GPU work is waited for outside of it
+				ctx.queue.submit(std::iter::once(commands));
+				ctx.device.poll(wgpu::Maintain::Wait);
+			}
+
+			let encoding = if bundled { "render bundle" } else { "encoded directly" };
+			println!("{encoding}: {:?} per frame", total / FRAMES);
+		}
 	}
 }
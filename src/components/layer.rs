@@ -0,0 +1,12 @@
+use crate::components::BlendMode;
+
+pub struct Layer {
+	/// Stable identifier, strokes and history entries refer to layers by id since indices change when reordering.
+	pub id: u32,
+	pub name: String,
+	pub tex: wgpu::Texture,
+	pub visible: bool,
+	pub opacity: f32,
+	/// How the layer is composited over the layers below it.
+	pub blend_mode: BlendMode,
+}
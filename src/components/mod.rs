@@ -8,7 +8,7 @@ use std::{
 };
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Pod, Zeroable)]
 pub struct Point {
 	pub x: i32,
 	pub y: i32,
@@ -23,13 +23,49 @@ impl<P: Into<f64>> From<winit::dpi::PhysicalPosition<P>> for Point {
 	}
 }
 
+/// Sub-pixel position, used for stroke points.
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+pub struct PointF {
+	pub x: f32,
+	pub y: f32,
+}
+
+impl<P: Into<f64>> From<winit::dpi::PhysicalPosition<P>> for PointF {
+	fn from(value: winit::dpi::PhysicalPosition<P>) -> Self {
+		PointF {
+			x: value.x.into() as f32,
+			y: value.y.into() as f32,
+		}
+	}
+}
+
+impl From<Point> for PointF {
+	fn from(value: Point) -> Self {
+		PointF {
+			x: value.x as f32,
+			y: value.y as f32,
+		}
+	}
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Pod, Zeroable)]
 pub struct Size {
 	pub w: u32,
 	pub h: u32,
 }
 
+impl From<Size> for wgpu::Extent3d {
+	fn from(value: Size) -> Self {
+		wgpu::Extent3d {
+			width: value.w,
+			height: value.h,
+			depth_or_array_layers: 1,
+		}
+	}
+}
+
 impl TryFrom<Point> for Size {
 	type Error = core::num::TryFromIntError;
 	fn try_from(value: Point) -> Result<Self, Self::Error> {
@@ -41,7 +77,7 @@ impl TryFrom<Point> for Size {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Pod, Zeroable)]
 pub struct Rect {
 	pub pos: Point,
 	pub size: Size,
@@ -64,6 +100,29 @@ impl Rect {
 		}
 		inside_dim!(x, w) && inside_dim!(y, h)
 	}
+
+	/// Smallest rect containing both `self` and `other`.
+	pub fn union(&self, other: Rect) -> Rect {
+		use std::cmp::{max, min};
+		let x = min(self.pos.x, other.pos.x);
+		let y = min(self.pos.y, other.pos.y);
+		let right = max(self.pos.x + self.size.w as i32, other.pos.x + other.size.w as i32);
+		let bottom = max(self.pos.y + self.size.h as i32, other.pos.y + other.size.h as i32);
+		Rect::new(x, y, (right - x) as u32, (bottom - y) as u32)
+	}
+
+	/// Overlapping area of both rects, `None` if they don't overlap.
+	pub fn intersection(&self, other: Rect) -> Option<Rect> {
+		use std::cmp::{max, min};
+		let x = max(self.pos.x, other.pos.x);
+		let y = max(self.pos.y, other.pos.y);
+		let right = min(self.pos.x + self.size.w as i32, other.pos.x + other.size.w as i32);
+		let bottom = min(self.pos.y + self.size.h as i32, other.pos.y + other.size.h as i32);
+		if right <= x || bottom <= y {
+			return None;
+		}
+		Some(Rect::new(x, y, (right - x) as u32, (bottom - y) as u32))
+	}
 }
 
 impl ops::AddAssign for Point {
@@ -92,6 +151,43 @@ impl ops::Sub for Point {
 	}
 }
 
+impl ops::AddAssign for PointF {
+	fn add_assign(&mut self, other: Self) {
+		self.x += other.x;
+		self.y += other.y;
+	}
+}
+
+impl ops::Add for PointF {
+	type Output = Self;
+	fn add(self, rhs: Self) -> Self::Output {
+		PointF {
+			x: self.x + rhs.x,
+			y: self.y + rhs.y,
+		}
+	}
+}
+
+impl ops::Sub for PointF {
+	type Output = Self;
+	fn sub(self, rhs: Self) -> Self::Output {
+		PointF {
+			x: self.x - rhs.x,
+			y: self.y - rhs.y,
+		}
+	}
+}
+
+impl ops::Mul<f32> for PointF {
+	type Output = Self;
+	fn mul(self, rhs: f32) -> Self::Output {
+		PointF {
+			x: self.x * rhs,
+			y: self.y * rhs,
+		}
+	}
+}
+
 impl ops::AddAssign<Point> for Rect {
 	fn add_assign(&mut self, other: Point) {
 		self.pos += other;
@@ -160,21 +256,44 @@ const STAGING_BUFFER_BYTES: u64 = 10;
 
 pub struct Context {
 	pub device: wgpu::Device,
+	pub queue: wgpu::Queue,
 	pub surface_format: wgpu::TextureFormat,
 	pipeline_map: HashMap<TypeId, Weak<Pipelines>>,
 	pub staging_belt: wgpu::util::StagingBelt,
 }
 
 impl Context {
-	pub fn new(device: wgpu::Device, surface_format: wgpu::TextureFormat) -> Context {
+	pub fn new(device: wgpu::Device, queue: wgpu::Queue, surface_format: wgpu::TextureFormat) -> Context {
 		Context {
 			device,
+			queue,
 			surface_format,
 			pipeline_map: HashMap::new(),
 			staging_belt: wgpu::util::StagingBelt::new(4 * STAGING_BUFFER_BYTES),
 		}
 	}
 
+	/// Context without a window, for tests. `None` if no adapter supports what the canvas needs.
+	#[cfg(test)]
+	pub fn headless() -> Option<Context> {
+		// Layers are read and written in the same pass, which GL can't do with Rgba8 textures
+		let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+		let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))?;
+		let (device, queue) = pollster::block_on(adapter.request_device(
+			&wgpu::DeviceDescriptor {
+				features: wgpu::Features::PUSH_CONSTANTS
+					| wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+				limits: wgpu::Limits {
+					max_push_constant_size: 64,
+					..wgpu::Limits::default()
+				},
+				label: None,
+			},
+			None,
+		)).ok()?;
+		Some(Context::new(device, queue, wgpu::TextureFormat::Rgba8Unorm))
+	}
+
 	pub fn get_pipelines<T: Component + 'static>(&mut self) -> Arc<Pipelines> {
 		if let Some(weak) = self.pipeline_map.get(&TypeId::of::<T>()) {
 			if let Some(arc) = weak.upgrade() {
@@ -196,5 +315,16 @@ macro_rules! add_component {
 	};
 }
 
+mod fill;
+mod history;
+mod layer;
+mod openraster;
+mod project;
+mod selection;
+mod shape;
+mod stabilizer;
+mod stroke_log;
+
 add_component!(canvas);
 add_component!(image);
+add_component!(preview);
@@ -0,0 +1,312 @@
+//! OpenRaster (`.ora`) files, a zip with the layer stack described in `stack.xml` and a PNG per layer.
+//! See https://www.openraster.org/baseline/file-layout-spec.html
+//!
+//! Only what the layers of a `Canvas` can represent is kept: nested stacks are flattened, layers are
+//! placed on a canvas sized image and composite ops without an equivalent `BlendMode` become `Normal`.
+
+use std::io::{self, Read, Seek, Write};
+
+use quick_xml::events::{BytesStart, Event};
+
+use crate::components::{BlendMode, Size};
+use crate::components::project::{check_size, encode_png, invalid, ProjectLayer};
+
+pub const ORA_EXTENSION: &str = "ora";
+
+const MIMETYPE: &str = "image/openraster";
+const THUMBNAIL_SIZE: u32 = 256;
+
+fn composite_op(mode: BlendMode) -> &'static str {
+	match mode {
+		BlendMode::Normal => "svg:src-over",
+		BlendMode::Multiply => "svg:multiply",
+		BlendMode::Screen => "svg:screen",
+		BlendMode::Overlay => "svg:overlay",
+		BlendMode::Darken => "svg:darken",
+		BlendMode::Lighten => "svg:lighten",
+	}
+}
+
+/// Composite ops without an equivalent, including the non-separable ones, become `Normal`.
+fn blend_mode(op: &str) -> BlendMode {
+	match op {
+		"svg:multiply" => BlendMode::Multiply,
+		"svg:screen" => BlendMode::Screen,
+		"svg:overlay" => BlendMode::Overlay,
+		"svg:darken" => BlendMode::Darken,
+		"svg:lighten" => BlendMode::Lighten,
+		_ => BlendMode::Normal,
+	}
+}
+
+fn rgba_image(size: Size, pixels: &[u8]) -> io::Result<image::RgbaImage> {
+	image::RgbaImage::from_raw(size.w, size.h, pixels.to_vec())
+		.ok_or_else(|| invalid("Layer pixels don't match the canvas size"))
+}
+
+/// Writes `layers` (bottom to top) and the flattened image `merged`, all with the canvas `size`.
+pub fn write(w: impl Write + Seek, size: Size, layers: &[ProjectLayer], merged: &[u8]) -> io::Result<()> {
+	use zip::{write::FileOptions, CompressionMethod};
+
+	let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+	let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+	let mut zip = zip::ZipWriter::new(w);
+
+	// Must be the first entry and uncompressed so the file type can be sniffed
+	zip.start_file("mimetype", stored)?;
+	zip.write_all(MIMETYPE.as_bytes())?;
+
+	let mut stack = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<image version=\"0.0.5\" w=\"{}\" h=\"{}\">\n<stack>\n", size.w, size.h);
+	for (i, layer) in layers.iter().enumerate().rev() {
+		stack += &format!(
+			"<layer name=\"{}\" src=\"data/layer{i}.png\" x=\"0\" y=\"0\" opacity=\"{}\" visibility=\"{}\" composite-op=\"{}\"/>\n",
+			quick_xml::escape::escape(&layer.name),
+			layer.opacity,
+			if layer.visible { "visible" } else { "hidden" },
+			composite_op(layer.blend_mode),
+		);
+	}
+	stack += "</stack>\n</image>\n";
+
+	zip.start_file("stack.xml", deflated)?;
+	zip.write_all(stack.as_bytes())?;
+
+	// PNGs are already compressed
+	for (i, layer) in layers.iter().enumerate() {
+		zip.start_file(format!("data/layer{i}.png"), stored)?;
+		zip.write_all(&encode_png(size, &layer.pixels)?)?;
+	}
+
+	let merged = rgba_image(size, merged)?;
+	zip.start_file("mergedimage.png", stored)?;
+	zip.write_all(&encode_png(size, merged.as_raw())?)?;
+
+	let scale = THUMBNAIL_SIZE as f32 / std::cmp::max(size.w, size.h) as f32;
+	let thumbnail = if scale < 1. {
+		let w = ((size.w as f32 * scale).round() as u32).max(1);
+		let h = ((size.h as f32 * scale).round() as u32).max(1);
+		image::imageops::thumbnail(&merged, w, h)
+	} else {
+		merged
+	};
+	zip.start_file("Thumbnails/thumbnail.png", stored)?;
+	zip.write_all(&encode_png(Size { w: thumbnail.width(), h: thumbnail.height() }, thumbnail.as_raw())?)?;
+
+	zip.finish()?;
+	Ok(())
+}
+
+/// A `layer` element of `stack.xml`.
+struct StackLayer {
+	src: String,
+	name: String,
+	pos: (i64, i64),
+	opacity: f32,
+	visible: bool,
+	blend_mode: BlendMode,
+}
+
+fn parse_layer(e: &BytesStart) -> io::Result<StackLayer> {
+	let mut layer = StackLayer {
+		src: String::new(),
+		name: String::new(),
+		pos: (0, 0),
+		opacity: 1.,
+		visible: true,
+		blend_mode: BlendMode::Normal,
+	};
+
+	for attr in e.attributes() {
+		let attr = attr.map_err(|e| invalid(e.to_string()))?;
+		let value = attr.unescape_value().map_err(|e| invalid(e.to_string()))?;
+		match attr.key.as_ref() {
+			b"src" => layer.src = value.into_owned(),
+			b"name" => layer.name = value.into_owned(),
+			b"x" => layer.pos.0 = value.parse().unwrap_or(0),
+			b"y" => layer.pos.1 = value.parse().unwrap_or(0),
+			b"opacity" => layer.opacity = value.parse::<f32>().unwrap_or(1.).clamp(0., 1.),
+			b"visibility" => layer.visible = value != "hidden",
+			b"composite-op" => layer.blend_mode = blend_mode(&value),
+			_ => {}
+		}
+	}
+	Ok(layer)
+}
+
+/// Reads the canvas size and the layers, bottom to top. Images wider or taller than `max_dimension`
+/// are rejected before anything is allocated for them.
+pub fn read(r: impl Read + Seek, max_dimension: u32) -> io::Result<(Size, Vec<ProjectLayer>)> {
+	let mut zip = zip::ZipArchive::new(r)?;
+
+	let mut stack = String::new();
+	zip.by_name("stack.xml")?.read_to_string(&mut stack)?;
+
+	let mut size = None;
+	let mut stack_layers = Vec::new();
+	let mut reader = quick_xml::Reader::from_str(&stack);
+	loop {
+		match reader.read_event().map_err(|e| invalid(e.to_string()))? {
+			Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"image" => {
+				let dim = |key: &[u8]| -> io::Result<u32> {
+					let attr = e.try_get_attribute(key)
+						.map_err(|e| invalid(e.to_string()))?
+						.ok_or_else(|| invalid("Missing image size"))?;
+					attr.unescape_value()
+						.map_err(|e| invalid(e.to_string()))?
+						.parse()
+						.map_err(|_| invalid("Invalid image size"))
+				};
+				size = Some(Size { w: dim(b"w")?, h: dim(b"h")? });
+			}
+			// Nested stacks are flattened, their layers are read in document order too
+			Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"layer" => {
+				stack_layers.push(parse_layer(&e)?);
+			}
+			Event::Eof => break,
+			_ => {}
+		}
+	}
+
+	let size = size.ok_or_else(|| invalid("Missing image element"))?;
+	check_size(size, max_dimension)?;
+
+	// The stack lists the topmost layer first
+	let mut layers = Vec::new();
+	for layer in stack_layers.into_iter().rev() {
+		let mut png_bytes = Vec::new();
+		zip.by_name(&layer.src)?.read_to_end(&mut png_bytes)?;
+		let mut decoder = image::io::Reader::with_format(io::Cursor::new(&png_bytes), image::ImageFormat::Png);
+		let mut limits = image::io::Limits::default();
+		limits.max_image_width = Some(max_dimension);
+		limits.max_image_height = Some(max_dimension);
+		decoder.limits(limits);
+		let img = decoder.decode()
+			.map_err(|e| invalid(e.to_string()))?
+			.into_rgba8();
+
+		let mut pixels = image::RgbaImage::new(size.w, size.h);
+		image::imageops::replace(&mut pixels, &img, layer.pos.0, layer.pos.1);
+
+		layers.push(ProjectLayer {
+			name: layer.name,
+			visible: layer.visible,
+			opacity: layer.opacity,
+			blend_mode: layer.blend_mode,
+			pixels: pixels.into_raw(),
+		});
+	}
+
+	if layers.is_empty() {
+		return Err(invalid("Image has no layers"));
+	}
+	Ok((size, layers))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SIZE: Size = Size { w: 4, h: 3 };
+	const MAX_DIMENSION: u32 = 64;
+
+	fn layer(name: &str, visible: bool, opacity: f32, blend_mode: BlendMode, seed: u8) -> ProjectLayer {
+		ProjectLayer {
+			name: String::from(name),
+			visible,
+			opacity,
+			blend_mode,
+			pixels: (0..4 * SIZE.w * SIZE.h).map(|i| seed.wrapping_mul(i as u8)).collect(),
+		}
+	}
+
+	fn round_trip(layers: &[ProjectLayer]) -> (Size, Vec<ProjectLayer>) {
+		let mut file = io::Cursor::new(Vec::new());
+		write(&mut file, SIZE, layers, &layers[0].pixels).unwrap();
+		file.set_position(0);
+		read(file, MAX_DIMENSION).unwrap()
+	}
+
+	#[test]
+	fn layers_round_trip() {
+		let modes = [BlendMode::Normal, BlendMode::Multiply, BlendMode::Screen, BlendMode::Overlay, BlendMode::Darken, BlendMode::Lighten];
+		let layers: Vec<ProjectLayer> = modes
+			.iter()
+			.enumerate()
+			.map(|(i, &mode)| layer(&format!("Layer <{i}> & co"), i % 3 != 0, i as f32 / 7., mode, i as u8 + 3))
+			.collect();
+
+		let (size, read) = round_trip(&layers);
+		assert_eq!((size.w, size.h), (SIZE.w, SIZE.h));
+		assert_eq!(read.len(), layers.len());
+		for (a, b) in layers.iter().zip(&read) {
+			assert_eq!(a.name, b.name);
+			assert_eq!(a.visible, b.visible);
+			assert_eq!(a.opacity, b.opacity);
+			assert_eq!(a.blend_mode, b.blend_mode);
+			assert_eq!(a.pixels, b.pixels);
+		}
+	}
+
+	#[test]
+	fn composite_ops_map_to_blend_modes() {
+		for mode in [BlendMode::Normal, BlendMode::Multiply, BlendMode::Screen, BlendMode::Overlay, BlendMode::Darken, BlendMode::Lighten] {
+			assert_eq!(blend_mode(composite_op(mode)), mode);
+			assert!(composite_op(mode).starts_with("svg:"));
+		}
+	}
+
+	/// Builds an archive with the given `stack.xml` and `a.png`.
+	fn archive(stack: &str, png: &[u8]) -> io::Cursor<Vec<u8>> {
+		use zip::write::FileOptions;
+
+		let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+		zip.start_file("stack.xml", FileOptions::default()).unwrap();
+		zip.write_all(stack.as_bytes()).unwrap();
+		zip.start_file("a.png", FileOptions::default()).unwrap();
+		zip.write_all(png).unwrap();
+		let mut file = zip.finish().unwrap();
+		file.set_position(0);
+		file
+	}
+
+	#[test]
+	fn unsupported_composite_ops_become_normal() {
+		let pixels = vec![200; (4 * SIZE.w * SIZE.h) as usize];
+		let file = archive(
+			&format!(
+				"<image w=\"{}\" h=\"{}\"><stack>\
+				<layer src=\"a.png\" composite-op=\"svg:color-dodge\"/>\
+				<layer src=\"a.png\" composite-op=\"krita:dissolve\"/>\
+				<layer src=\"a.png\"/>\
+				</stack></image>",
+				SIZE.w, SIZE.h,
+			),
+			&encode_png(SIZE, &pixels).unwrap(),
+		);
+
+		let (_, layers) = read(file, MAX_DIMENSION).unwrap();
+		assert_eq!(layers.len(), 3);
+		for layer in layers {
+			assert_eq!(layer.blend_mode, BlendMode::Normal);
+			assert_eq!(layer.pixels, pixels);
+		}
+	}
+	#[test]
+	fn oversized_image_is_rejected() {
+		let png = encode_png(SIZE, &vec![0; (4 * SIZE.w * SIZE.h) as usize]).unwrap();
+		for (w, h) in [(0, 3), (100000, 100000), (u32::MAX, u32::MAX), (MAX_DIMENSION + 1, 1)] {
+			let file = archive(&format!("<image w=\"{w}\" h=\"{h}\"><stack><layer src=\"a.png\"/></stack></image>"), &png);
+			let err = read(file, MAX_DIMENSION).err().unwrap();
+			assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{w}x{h}");
+		}
+	}
+
+	#[test]
+	fn oversized_layer_is_rejected() {
+		let wide = Size { w: MAX_DIMENSION + 1, h: 1 };
+		let png = encode_png(wide, &vec![0; (4 * wide.w * wide.h) as usize]).unwrap();
+		let file = archive(&format!("<image w=\"{}\" h=\"{}\"><stack><layer src=\"a.png\"/></stack></image>", SIZE.w, SIZE.h), &png);
+		let err = read(file, MAX_DIMENSION).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+}
@@ -0,0 +1,235 @@
+use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
+
+use crate::components::{self, Point, PointF, Rect, Size, ViewTransform, Context, Pipelines, RectViewportClipSpace};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct PreviewInput {
+	origin: [f32; 2],
+	scale: f32,
+	radius: f32,
+	color: [f32; 4],
+	count: u32,
+	filled: u32,
+	dash: f32,
+	phase: f32,
+}
+
+/// Draws a shape being edited over the canvas, without modifying it.
+pub struct Preview {
+	pipelines: std::sync::Arc<Pipelines>,
+	vertices: Vec<PointF>,
+	binding_group: Option<wgpu::BindGroup>,
+	filled: bool,
+	color: [f32; 4],
+	radius: f32,
+	/// Length of the dashes in framebuffer pixels and how far they've moved along the outline, solid if 0
+	dash: f32,
+	phase: f32,
+	view: ViewTransform,
+}
+
+impl components::Component for Preview {
+	fn generate_pipelines(ctx: &Context) -> Pipelines {
+		let shader = ctx.device.create_shader_module(wgpu::include_wgsl!("shaders/preview.wgsl"));
+
+		let binding_group_layout = ctx.device.create_bind_group_layout(
+			&wgpu::BindGroupLayoutDescriptor {
+				label: Some("Preview(Binding Group Layout)"),
+				entries: &[
+					wgpu::BindGroupLayoutEntry {
+						binding: 0,
+						visibility: wgpu::ShaderStages::FRAGMENT,
+						ty: wgpu::BindingType::Buffer {
+							ty: wgpu::BufferBindingType::Storage { read_only: true },
+							has_dynamic_offset: false,
+							min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<PointF>() as u64),
+						},
+						count: None,
+					}
+				]
+			}
+		);
+
+		let render_pipeline_layout = ctx.device.create_pipeline_layout(
+			&wgpu::PipelineLayoutDescriptor {
+				label: Some("Preview(Pipeline Layout)"),
+				bind_group_layouts: &[&binding_group_layout],
+
+				push_constant_ranges: &[
+					wgpu::PushConstantRange {
+						stages: wgpu::ShaderStages::FRAGMENT,
+						range: (0..std::mem::size_of::<PreviewInput>() as u32),
+					}
+				],
+			}
+		);
+
+		let render_pipeline = ctx.device.create_render_pipeline(
+			&wgpu::RenderPipelineDescriptor {
+				label: Some("Preview(Render Pipeline)"),
+				layout: Some(&render_pipeline_layout),
+				vertex: wgpu::VertexState {
+					module: &shader,
+					entry_point: "vs_main",
+					buffers: &[],
+				},
+				fragment: Some(wgpu::FragmentState {
+					module: &shader,
+					entry_point: "fs_main",
+					targets: &[Some(wgpu::ColorTargetState {
+						format: ctx.surface_format,
+						blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+						write_mask: wgpu::ColorWrites::ALL,
+					})],
+				}),
+				primitive: wgpu::PrimitiveState {
+					topology: wgpu::PrimitiveTopology::TriangleList,
+					strip_index_format: None,
+					front_face: wgpu::FrontFace::Ccw,
+					cull_mode: None,
+					polygon_mode: wgpu::PolygonMode::Fill,
+					unclipped_depth: false,
+					conservative: false,
+				},
+				depth_stencil: None,
+				multisample: wgpu::MultisampleState {
+					count: 1,
+					mask: !0,
+					alpha_to_coverage_enabled: false
+				},
+				multiview: None
+			}
+		);
+
+		Pipelines {
+			render: vec![render_pipeline],
+			compute: vec![],
+		}
+	}
+
+	fn new(ctx: &mut Context) -> Box<Self> {
+		Box::new(Self {
+			pipelines: ctx.get_pipelines::<Self>(),
+			vertices: vec![],
+			binding_group: None,
+			filled: false,
+			color: [0., 0., 0., 0.],
+			radius: 0.,
+			dash: 0.,
+			phase: 0.,
+			view: ViewTransform::default(),
+		})
+	}
+
+	fn render(&mut self, encoder: &mut wgpu::CommandEncoder, _: &mut Context, output: &wgpu::TextureView, viewport: Rect, clip_space: Option<Rect>) {
+		let binding = match &self.binding_group {
+			None => return,
+			Some(b) => b,
+		};
+
+		// Every fragment goes through all the vertices, so only the area around the outline is drawn
+		let origin = [viewport.pos.x as f32 + self.view.offset[0], viewport.pos.y as f32 + self.view.offset[1]];
+		let clip = match self.bounds(origin).and_then(|b| b.intersection(clip_space.unwrap_or(viewport))) {
+			None => return,
+			Some(c) => c,
+		};
+
+		let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+			label: Some("Preview(Render Pass)"),
+			color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+				view: output,
+				resolve_target: None,
+				ops: wgpu::Operations {
+					load: wgpu::LoadOp::Load,
+					store: true,
+				},
+			})],
+			depth_stencil_attachment: None,
+		});
+
+		render_pass.set_pipeline(&self.pipelines.render[0]);
+		render_pass.set_viewport_rect(viewport);
+		render_pass.set_clipspace_rect(Some(clip));
+		render_pass.set_bind_group(0, binding, &[]);
+
+		let input = PreviewInput {
+			origin,
+			scale: self.view.scale,
+			radius: self.radius,
+			color: self.color,
+			count: self.vertices.len() as u32,
+			filled: self.filled as u32,
+			dash: self.dash,
+			phase: self.phase,
+		};
+		render_pass.set_push_constants(wgpu::ShaderStages::FRAGMENT, 0, bytemuck::bytes_of(&input));
+		render_pass.draw(0..6, 0..1);
+	}
+
+	fn min_size() -> Option<components::Size> {
+		None
+	}
+}
+
+impl Preview {
+	/// Framebuffer area the shape is drawn in, for the texture's top-left corner at `origin`.
+	fn bounds(&self, origin: [f32; 2]) -> Option<Rect> {
+		let first = *self.vertices.first()?;
+		let (min, max) = self.vertices.iter().fold((first, first), |(min, max), v| {
+			(PointF { x: min.x.min(v.x), y: min.y.min(v.y) }, PointF { x: max.x.max(v.x), y: max.y.max(v.y) })
+		});
+
+		// Edges are smoothed over a framebuffer pixel
+		let margin = self.radius * self.view.scale + 1.;
+		let x0 = (origin[0] + min.x * self.view.scale - margin).floor() as i32;
+		let y0 = (origin[1] + min.y * self.view.scale - margin).floor() as i32;
+		let x1 = (origin[0] + max.x * self.view.scale + margin).ceil() as i32;
+		let y1 = (origin[1] + max.y * self.view.scale + margin).ceil() as i32;
+		Some(Rect { pos: Point { x: x0, y: y0 }, size: Size { w: (x1 - x0) as u32, h: (y1 - y0) as u32 } })
+	}
+
+	pub fn set_view(&mut self, view: ViewTransform) {
+		self.view = view;
+	}
+
+	/// Draws outlines as a one pixel wide line of alternating black and white dashes,
+	/// "marching ants" when `phase` changes over time.
+	pub fn set_dashes(&mut self, dash: f32, phase: f32) {
+		self.dash = dash;
+		self.phase = phase;
+	}
+
+	/// Shape drawn from now on, an outline with `radius` unless `filled`. `vertices` are in texture coordinates.
+	pub fn set_shape(&mut self, ctx: &Context, vertices: Vec<PointF>, filled: bool, color: [f32; 4], radius: f32) {
+		self.filled = filled;
+		self.color = color;
+		self.radius = radius;
+
+		// The buffer only changes with the vertices
+		if self.binding_group.is_some() && vertices == self.vertices {
+			return;
+		}
+
+		let buffer = ctx.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+			label: Some("Preview(Vertex Buffer)"),
+			contents: bytemuck::cast_slice(&vertices),
+			usage: wgpu::BufferUsages::STORAGE,
+		});
+
+		self.binding_group = Some(ctx.device.create_bind_group(
+			&wgpu::BindGroupDescriptor {
+				label: Some("Preview(Binding group 0)"),
+				layout: &self.pipelines.render[0].get_bind_group_layout(0),
+				entries: &[
+					wgpu::BindGroupEntry {
+						binding: 0,
+						resource: buffer.as_entire_binding(),
+					},
+				],
+			}
+		));
+		self.vertices = vertices;
+	}
+}
@@ -0,0 +1,586 @@
+//! Native project files.
+//!
+//! A project file is the `MAGIC` bytes, the format version (major and minor, `u16` each) and a sequence
+//! of chunks until the end of the file. Each chunk is a 4 byte tag, the payload length as `u32` and the
+//! payload. All numbers are little endian.
+//!
+//! Minor versions only add chunks or append fields at the end of existing payloads, so readers skip
+//! unknown chunks and ignore trailing bytes. A new major version is not readable by older versions.
+
+use std::io::{self, Read, Write};
+
+use crate::components::{BlendMode, LinePoint, PointF, Size};
+use crate::components::stroke_log::{LogEntry, LoggedStroke};
+
+pub const PROJECT_EXTENSION: &str = "pntr";
+
+const MAGIC: &[u8; 4] = b"PNTR";
+const VERSION_MAJOR: u16 = 1;
+const VERSION_MINOR: u16 = 2;
+
+/// Canvas size, background and active layer index. Must come before the layers.
+const CANVAS_CHUNK: &[u8; 4] = b"CNVS";
+/// Brush settings.
+const BRUSH_CHUNK: &[u8; 4] = b"BRSH";
+/// One per layer, bottom to top: properties followed by the pixels as a PNG.
+const LAYER_CHUNK: &[u8; 4] = b"LAYR";
+/// Stroke log with integer positions, written by 1.1. Entries refer to layers by their index.
+const STROKES_CHUNK: &[u8; 4] = b"STRK";
+/// Stroke log with sub-pixel positions, since 1.2. Otherwise the same as `STROKES_CHUNK`.
+const SUBPIXEL_STROKES_CHUNK: &[u8; 4] = b"STKF";
+
+const CLEAR_ENTRY: u8 = 0;
+const STROKE_ENTRY: u8 = 1;
+
+pub struct BrushSettings {
+	pub radius: f32,
+	pub color: [f32; 3],
+	pub opacity: f32,
+	pub blend_mode: BlendMode,
+	pub antialias: bool,
+	pub linear_blending: bool,
+	pub eraser: bool,
+}
+
+pub struct ProjectLayer {
+	pub name: String,
+	pub visible: bool,
+	pub opacity: f32,
+	pub blend_mode: BlendMode,
+	/// Rgba8 pixels, with the size of the canvas
+	pub pixels: Vec<u8>,
+}
+
+/// Everything a project file stores, independent of the GPU resources of a `Canvas`.
+pub struct Project {
+	pub size: Size,
+	pub background: [f32; 3],
+	pub active: usize,
+	pub brush: BrushSettings,
+	/// Bottom to top
+	pub layers: Vec<ProjectLayer>,
+	/// Stroke log with layer indices as ids, empty if the file has none
+	pub strokes: Vec<LogEntry>,
+}
+
+pub fn invalid(msg: impl Into<String>) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Checks a canvas size read from a file before anything is allocated for it: not empty,
+/// no side longer than `max_dimension` and its Rgba8 pixels addressable.
+pub fn check_size(size: Size, max_dimension: u32) -> io::Result<()> {
+	if size.w == 0 || size.h == 0 {
+		return Err(invalid("Empty canvas"));
+	}
+	if size.w > max_dimension || size.h > max_dimension {
+		return Err(invalid(format!("Canvas of {}x{} is bigger than the device allows", size.w, size.h)));
+	}
+	(size.w as usize).checked_mul(size.h as usize)
+		.and_then(|n| n.checked_mul(4))
+		.ok_or_else(|| invalid("Canvas doesn't fit in memory"))?;
+	Ok(())
+}
+
+/// Appends values to a chunk payload.
+#[derive(Default)]
+struct ChunkWriter(Vec<u8>);
+
+impl ChunkWriter {
+	fn u8(&mut self, v: u8) {
+		self.0.push(v);
+	}
+
+	fn u32(&mut self, v: u32) {
+		self.0.extend_from_slice(&v.to_le_bytes());
+	}
+
+	fn f32(&mut self, v: f32) {
+		self.0.extend_from_slice(&v.to_le_bytes());
+	}
+
+	/// Length prefixed
+	fn bytes(&mut self, v: &[u8]) {
+		self.u32(v.len() as u32);
+		self.0.extend_from_slice(v);
+	}
+
+	fn finish(self, tag: &[u8; 4], w: &mut impl Write) -> io::Result<()> {
+		w.write_all(tag)?;
+		w.write_all(&(self.0.len() as u32).to_le_bytes())?;
+		w.write_all(&self.0)
+	}
+}
+
+/// Reads values from a chunk payload, failing when it ends early.
+struct ChunkReader<'a>(&'a [u8]);
+
+impl<'a> ChunkReader<'a> {
+	fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+		if self.0.len() < n {
+			return Err(invalid("Truncated chunk"));
+		}
+		let (head, tail) = self.0.split_at(n);
+		self.0 = tail;
+		Ok(head)
+	}
+
+	fn u8(&mut self) -> io::Result<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn bool(&mut self) -> io::Result<bool> {
+		Ok(self.u8()? != 0)
+	}
+
+	fn u32(&mut self) -> io::Result<u32> {
+		Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+	}
+
+	fn i32(&mut self) -> io::Result<i32> {
+		Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+	}
+
+	fn f32(&mut self) -> io::Result<f32> {
+		Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+	}
+
+	fn color(&mut self) -> io::Result<[f32; 3]> {
+		Ok([self.f32()?, self.f32()?, self.f32()?])
+	}
+
+	fn blend_mode(&mut self) -> io::Result<BlendMode> {
+		let v = self.u32()?;
+		BlendMode::try_from(v).map_err(|_| invalid(format!("Unknown blend mode {v}")))
+	}
+
+	fn bytes(&mut self) -> io::Result<&'a [u8]> {
+		let len = self.u32()? as usize;
+		self.take(len)
+	}
+}
+
+/// Rgba8 `pixels` of an image of `size` as a PNG.
+pub fn encode_png(size: Size, pixels: &[u8]) -> io::Result<Vec<u8>> {
+	let mut png_bytes = Vec::new();
+	let mut encoder = png::Encoder::new(&mut png_bytes, size.w, size.h);
+	encoder.set_color(png::ColorType::Rgba);
+	encoder.set_depth(png::BitDepth::Eight);
+	encoder.write_header()?.write_image_data(pixels)?;
+	Ok(png_bytes)
+}
+
+fn decode_png(size: Size, png_bytes: &[u8]) -> io::Result<Vec<u8>> {
+	let mut decoder = png::Decoder::new(png_bytes);
+	decoder.set_transformations(png::Transformations::EXPAND);
+	let mut reader = decoder.read_info().map_err(|e| invalid(e.to_string()))?;
+	// The declared size is checked before the buffer for it is allocated
+	if (reader.info().width, reader.info().height) != (size.w, size.h) {
+		return Err(invalid("Layer image doesn't match the canvas"));
+	}
+	let mut pixels = vec![0; reader.output_buffer_size()];
+	let info = reader.next_frame(&mut pixels).map_err(|e| invalid(e.to_string()))?;
+
+	if (info.width, info.height) != (size.w, size.h)
+		|| info.color_type != png::ColorType::Rgba
+		|| info.bit_depth != png::BitDepth::Eight {
+		return Err(invalid("Layer image doesn't match the canvas"));
+	}
+	pixels.truncate(info.buffer_size());
+	Ok(pixels)
+}
+
+impl Project {
+	pub fn write(&self, mut w: impl Write) -> io::Result<()> {
+		w.write_all(MAGIC)?;
+		w.write_all(&VERSION_MAJOR.to_le_bytes())?;
+		w.write_all(&VERSION_MINOR.to_le_bytes())?;
+
+		let mut canvas = ChunkWriter::default();
+		canvas.u32(self.size.w);
+		canvas.u32(self.size.h);
+		self.background.iter().for_each(|&c| canvas.f32(c));
+		canvas.u32(self.active as u32);
+		canvas.finish(CANVAS_CHUNK, &mut w)?;
+
+		let mut brush = ChunkWriter::default();
+		brush.f32(self.brush.radius);
+		self.brush.color.iter().for_each(|&c| brush.f32(c));
+		brush.f32(self.brush.opacity);
+		brush.u32(self.brush.blend_mode as u32);
+		brush.u8(self.brush.antialias as u8);
+		brush.u8(self.brush.linear_blending as u8);
+		brush.u8(self.brush.eraser as u8);
+		brush.finish(BRUSH_CHUNK, &mut w)?;
+
+		for layer in &self.layers {
+			let mut chunk = ChunkWriter::default();
+			chunk.bytes(layer.name.as_bytes());
+			chunk.u8(layer.visible as u8);
+			chunk.f32(layer.opacity);
+			chunk.u32(layer.blend_mode as u32);
+			chunk.bytes(&encode_png(self.size, &layer.pixels)?);
+			chunk.finish(LAYER_CHUNK, &mut w)?;
+		}
+
+		if !self.strokes.is_empty() {
+			let mut chunk = ChunkWriter::default();
+			chunk.u32(self.strokes.len() as u32);
+			for entry in &self.strokes {
+				match entry {
+					LogEntry::Clear { layer } => {
+						chunk.u8(CLEAR_ENTRY);
+						chunk.u32(*layer);
+					}
+					LogEntry::Stroke(s) => {
+						chunk.u8(STROKE_ENTRY);
+						chunk.u32(s.layer);
+						s.color.iter().for_each(|&c| chunk.f32(c));
+						chunk.f32(s.opacity);
+						chunk.u32(s.blend_mode as u32);
+						chunk.u8(s.erase as u8);
+						chunk.u32(s.points.len() as u32);
+						for p in &s.points {
+							chunk.f32(p.pos.x);
+							chunk.f32(p.pos.y);
+							chunk.f32(p.radius);
+							chunk.f32(p.opacity);
+						}
+					}
+				}
+			}
+			chunk.finish(SUBPIXEL_STROKES_CHUNK, &mut w)?;
+		}
+
+		w.flush()
+	}
+
+	/// Reads a project whose canvas has no side longer than `max_dimension`.
+	pub fn read(mut r: impl Read, max_dimension: u32) -> io::Result<Project> {
+		let mut header = [0; 8];
+		r.read_exact(&mut header)?;
+		if &header[..4] != MAGIC {
+			return Err(invalid("Not a pntr project"));
+		}
+		let major = u16::from_le_bytes([header[4], header[5]]);
+		if major > VERSION_MAJOR {
+			let minor = u16::from_le_bytes([header[6], header[7]]);
+			return Err(invalid(format!("Project version {major}.{minor} is newer than the supported {VERSION_MAJOR}.{VERSION_MINOR}")));
+		}
+
+		let mut data = Vec::new();
+		r.read_to_end(&mut data)?;
+		let mut file = ChunkReader(&data);
+
+		let mut canvas = None;
+		let mut brush = None;
+		let mut layers = Vec::new();
+		let mut strokes = Vec::new();
+
+		while !file.0.is_empty() {
+			let tag = file.take(4)?;
+			let mut chunk = ChunkReader(file.bytes()?);
+
+			match tag {
+				t if t == CANVAS_CHUNK => {
+					let size = Size { w: chunk.u32()?, h: chunk.u32()? };
+					check_size(size, max_dimension)?;
+					canvas = Some((size, chunk.color()?, chunk.u32()? as usize));
+				}
+				t if t == BRUSH_CHUNK => {
+					brush = Some(BrushSettings {
+						radius: chunk.f32()?,
+						color: chunk.color()?,
+						opacity: chunk.f32()?,
+						blend_mode: chunk.blend_mode()?,
+						antialias: chunk.bool()?,
+						linear_blending: chunk.bool()?,
+						eraser: chunk.bool()?,
+					});
+				}
+				t if t == LAYER_CHUNK => {
+					let size = canvas.ok_or_else(|| invalid("Layer before the canvas chunk"))?.0;
+					let name = String::from_utf8(chunk.bytes()?.to_vec()).map_err(|e| invalid(e.to_string()))?;
+					layers.push(ProjectLayer {
+						name,
+						visible: chunk.bool()?,
+						opacity: chunk.f32()?,
+						blend_mode: chunk.blend_mode()?,
+						pixels: decode_png(size, chunk.bytes()?)?,
+					});
+				}
+				t if t == STROKES_CHUNK || t == SUBPIXEL_STROKES_CHUNK => {
+					let subpixel = t == SUBPIXEL_STROKES_CHUNK;
+					for _ in 0..chunk.u32()? {
+						let kind = chunk.u8()?;
+						let layer = chunk.u32()?;
+						strokes.push(match kind {
+							CLEAR_ENTRY => LogEntry::Clear { layer },
+							STROKE_ENTRY => {
+								let color = chunk.color()?;
+								let opacity = chunk.f32()?;
+								let blend_mode = chunk.blend_mode()?;
+								let erase = chunk.bool()?;
+								let points = (0..chunk.u32()?).map(|_| Ok(LinePoint {
+									pos: if subpixel {
+										PointF { x: chunk.f32()?, y: chunk.f32()? }
+									} else {
+										// Integer positions were texel indices
+										PointF { x: chunk.i32()? as f32 + 0.5, y: chunk.i32()? as f32 + 0.5 }
+									},
+									radius: chunk.f32()?,
+									opacity: chunk.f32()?,
+								})).collect::<io::Result<_>>()?;
+								LogEntry::Stroke(LoggedStroke { layer, points, color, opacity, blend_mode, erase })
+							}
+							_ => return Err(invalid(format!("Unknown stroke log entry {kind}"))),
+						});
+					}
+				}
+				// Written by a newer version
+				_ => {}
+			}
+		}
+
+		let (size, background, active) = canvas.ok_or_else(|| invalid("Missing canvas chunk"))?;
+		if layers.is_empty() {
+			return Err(invalid("Project has no layers"));
+		}
+
+		Ok(Project {
+			size,
+			background,
+			active: active.min(layers.len() - 1),
+			brush: brush.ok_or_else(|| invalid("Missing brush chunk"))?,
+			layers,
+			strokes,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MAX_DIMENSION: u32 = 64;
+
+	fn project() -> Project {
+		let size = Size { w: 3, h: 2 };
+		let layer = |name: &str, fill: u8, visible: bool| ProjectLayer {
+			name: String::from(name),
+			visible,
+			opacity: fill as f32 / 255.,
+			blend_mode: BlendMode::Screen,
+			pixels: (0..4 * 6).map(|i| fill.wrapping_add(i)).collect(),
+		};
+		let points = vec![
+			LinePoint { pos: PointF { x: 0.5, y: 1.25 }, radius: 3., opacity: 1. },
+			LinePoint { pos: PointF { x: 2.75, y: 0.5 }, radius: 2.5, opacity: 0.5 },
+		];
+		Project {
+			size,
+			background: [0.1, 0.2, 0.3],
+			active: 1,
+			brush: BrushSettings {
+				radius: 4.,
+				color: [1., 0.5, 0.],
+				opacity: 0.75,
+				blend_mode: BlendMode::Overlay,
+				antialias: false,
+				linear_blending: true,
+				eraser: true,
+			},
+			layers: vec![layer("Background", 10, true), layer("Ink", 21, false), layer("Notes", 40, true)],
+			strokes: vec![
+				LogEntry::Stroke(LoggedStroke { layer: 1, points, color: [0., 1., 0.], opacity: 0.8, blend_mode: BlendMode::Darken, erase: false }),
+				LogEntry::Clear { layer: 2 },
+			],
+		}
+	}
+
+	fn bytes(project: &Project) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		project.write(&mut bytes).unwrap();
+		bytes
+	}
+
+	fn chunk(tag: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		ChunkWriter(payload.to_vec()).finish(tag, &mut bytes).unwrap();
+		bytes
+	}
+
+	fn assert_same(a: &Project, b: &Project) {
+		assert_eq!((a.size.w, a.size.h), (b.size.w, b.size.h));
+		assert_eq!(a.background, b.background);
+		assert_eq!(a.active, b.active);
+
+		let (x, y) = (&a.brush, &b.brush);
+		assert_eq!((x.radius, x.color, x.opacity, x.blend_mode), (y.radius, y.color, y.opacity, y.blend_mode));
+		assert_eq!((x.antialias, x.linear_blending, x.eraser), (y.antialias, y.linear_blending, y.eraser));
+
+		assert_eq!(a.layers.len(), b.layers.len());
+		for (x, y) in a.layers.iter().zip(&b.layers) {
+			assert_eq!((&x.name, x.visible, x.opacity, x.blend_mode), (&y.name, y.visible, y.opacity, y.blend_mode));
+			assert_eq!(x.pixels, y.pixels);
+		}
+
+		assert_eq!(a.strokes.len(), b.strokes.len());
+		for (x, y) in a.strokes.iter().zip(&b.strokes) {
+			match (x, y) {
+				(LogEntry::Clear { layer: x }, LogEntry::Clear { layer: y }) => assert_eq!(x, y),
+				(LogEntry::Stroke(x), LogEntry::Stroke(y)) => {
+					assert_eq!((x.layer, x.color, x.opacity, x.blend_mode, x.erase), (y.layer, y.color, y.opacity, y.blend_mode, y.erase));
+					assert_eq!(x.points.len(), y.points.len());
+					for (p, q) in x.points.iter().zip(&y.points) {
+						assert_eq!((p.pos, p.radius, p.opacity), (q.pos, q.radius, q.opacity));
+					}
+				}
+				_ => panic!("Stroke log entries differ"),
+			}
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		let project = project();
+		let read = Project::read(bytes(&project).as_slice(), MAX_DIMENSION).unwrap();
+		assert_same(&project, &read);
+	}
+
+	#[test]
+	fn legacy_strokes_are_read_at_texel_centers() {
+		let mut project = project();
+		project.strokes.clear();
+		let mut file = bytes(&project);
+
+		let mut chunk = ChunkWriter::default();
+		chunk.u32(2);
+		chunk.u8(CLEAR_ENTRY);
+		chunk.u32(0);
+		chunk.u8(STROKE_ENTRY);
+		chunk.u32(1);
+		[0.25, 0.5, 1.].iter().for_each(|&c| chunk.f32(c));
+		chunk.f32(0.6);
+		chunk.u32(BlendMode::Screen as u32);
+		chunk.u8(1);
+		chunk.u32(2);
+		for (x, y, radius) in [(0, 1, 2.), (-3, 2, 4.5)] {
+			// Positions were i32 texel indices before sub-pixel strokes
+			chunk.u32(x as u32);
+			chunk.u32(y as u32);
+			chunk.f32(radius);
+			chunk.f32(0.75);
+		}
+		chunk.finish(STROKES_CHUNK, &mut file).unwrap();
+
+		let read = Project::read(file.as_slice(), MAX_DIMENSION).unwrap();
+		assert!(matches!(read.strokes[0], LogEntry::Clear { layer: 0 }));
+		let stroke = match &read.strokes[1] {
+			LogEntry::Stroke(s) => s,
+			LogEntry::Clear { .. } => panic!("Expected a stroke"),
+		};
+		assert_eq!((stroke.layer, stroke.color, stroke.opacity), (1, [0.25, 0.5, 1.], 0.6));
+		assert_eq!((stroke.blend_mode, stroke.erase), (BlendMode::Screen, true));
+		let points: Vec<_> = stroke.points.iter().map(|p| (p.pos, p.radius, p.opacity)).collect();
+		assert_eq!(points, [(PointF { x: 0.5, y: 1.5 }, 2., 0.75), (PointF { x: -2.5, y: 2.5 }, 4.5, 0.75)]);
+	}
+
+	#[test]
+	fn newer_minor_version_and_unknown_chunks_are_skipped() {
+		let project = project();
+		let mut file = bytes(&project);
+		file[6..8].copy_from_slice(&(VERSION_MINOR + 1).to_le_bytes());
+
+		// Before and after the known chunks
+		let unknown = chunk(b"NEWS", &[1, 2, 3, 4, 5]);
+		file.splice(8..8, unknown.iter().copied());
+		file.extend(chunk(b"NEWE", &[]));
+
+		let read = Project::read(file.as_slice(), MAX_DIMENSION).unwrap();
+		assert_same(&project, &read);
+	}
+
+	#[test]
+	fn newer_major_version_is_rejected() {
+		let mut file = bytes(&project());
+		file[4..6].copy_from_slice(&(VERSION_MAJOR + 1).to_le_bytes());
+		let err = Project::read(file.as_slice(), MAX_DIMENSION).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn empty_canvas_is_rejected() {
+		let mut file = bytes(&project());
+		// The canvas chunk comes first, its width right after the tag and length
+		file[16..20].copy_from_slice(&0u32.to_le_bytes());
+		assert!(Project::read(file.as_slice(), MAX_DIMENSION).is_err());
+	}
+
+	/// Bytes of a project with its canvas chunk, the first one, set to `size`.
+	fn with_canvas_size(project: &Project, size: Size) -> Vec<u8> {
+		let mut file = bytes(project);
+		file[16..20].copy_from_slice(&size.w.to_le_bytes());
+		file[20..24].copy_from_slice(&size.h.to_le_bytes());
+		file
+	}
+
+	#[test]
+	fn oversized_canvas_is_rejected() {
+		for size in [Size { w: MAX_DIMENSION + 1, h: 2 }, Size { w: 3, h: 100_000 }, Size { w: u32::MAX, h: u32::MAX }] {
+			let err = Project::read(with_canvas_size(&project(), size).as_slice(), MAX_DIMENSION).err().unwrap();
+			assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		}
+		let err = Project::read(with_canvas_size(&project(), Size { w: 1 << 31, h: 1 << 31 }).as_slice(), u32::MAX).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn layers_must_match_the_canvas() {
+		// Layers of 3x2 in a canvas of 2x3
+		let err = Project::read(with_canvas_size(&project(), Size { w: 2, h: 3 }).as_slice(), MAX_DIMENSION).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+		// A layer claiming to be huge is rejected before its pixels are allocated
+		let mut png_bytes = Vec::new();
+		let mut encoder = png::Encoder::new(&mut png_bytes, 100_000, 100_000);
+		encoder.set_color(png::ColorType::Rgba);
+		drop(encoder.write_header().unwrap());
+		let mut layer = ChunkWriter::default();
+		layer.bytes(b"Huge");
+		layer.u8(1);
+		layer.f32(1.);
+		layer.u32(BlendMode::Normal as u32);
+		layer.bytes(&png_bytes);
+		let mut file = bytes(&project());
+		layer.finish(LAYER_CHUNK, &mut file).unwrap();
+
+		let err = Project::read(file.as_slice(), MAX_DIMENSION).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn truncated_input_is_an_error() {
+		let file = bytes(&project());
+		// Cut inside the header, a chunk header and the last payload
+		for len in [0, 5, 8, 10, file.len() - 1] {
+			assert!(Project::read(&file[..len], MAX_DIMENSION).is_err(), "Read {len} bytes");
+		}
+		// Ends that fall between chunks may be valid, none may panic
+		for len in 0..file.len() {
+			let _ = Project::read(&file[..len], MAX_DIMENSION);
+		}
+	}
+
+	#[test]
+	fn corrupt_input_does_not_panic() {
+		let file = bytes(&project());
+		for i in 0..file.len() {
+			for flip in [0x01, 0x80, 0xff] {
+				let mut corrupt = file.clone();
+				corrupt[i] ^= flip;
+				let _ = Project::read(corrupt.as_slice(), MAX_DIMENSION);
+			}
+		}
+	}
+}
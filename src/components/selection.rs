@@ -0,0 +1,255 @@
+use crate::components::{Point, PointF, Rect, Size};
+use crate::components::fill::source_over;
+use crate::components::shape;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SelectionKind {
+	Rectangle,
+	/// Freehand outline, closed with a straight line.
+	Lasso,
+}
+
+impl SelectionKind {
+	/// Cycles through all selection tools, `None` being drawing.
+	pub fn next(kind: Option<SelectionKind>) -> Option<SelectionKind> {
+		use SelectionKind::*;
+		match kind {
+			None => Some(Rectangle),
+			Some(Rectangle) => Some(Lasso),
+			Some(Lasso) => None,
+		}
+	}
+}
+
+/// Selected area of the canvas, with antialiased edges.
+pub struct Selection {
+	/// Closed outline in texture coordinates, the first vertex is repeated at the end
+	outline: Vec<PointF>,
+	/// Area covered by the outline, inside the canvas
+	rect: Rect,
+	/// Selected fraction of each pixel of `rect`, as rows
+	coverage: Vec<f32>,
+}
+
+impl Selection {
+	/// Area enclosed by `outline` inside the canvas, `None` if it's empty.
+	pub fn new(mut outline: Vec<PointF>, canvas: Size) -> Option<Selection> {
+		if outline.len() < 3 {
+			return None;
+		}
+		outline.push(outline[0]);
+
+		let rect = shape::bounds(&outline, canvas)?;
+		let coverage = shape::polygon_coverage(&outline, rect);
+		if coverage.iter().all(|c| *c == 0.) {
+			return None;
+		}
+		Some(Selection { outline, rect, coverage })
+	}
+
+	pub fn outline(&self) -> &[PointF] {
+		&self.outline
+	}
+
+	pub fn rect(&self) -> Rect {
+		self.rect
+	}
+
+	/// Selected fraction of the pixel at `p`.
+	pub fn coverage(&self, p: Point) -> f32 {
+		let local = p - self.rect.pos;
+		if local.x < 0 || local.y < 0 || local.x >= self.rect.size.w as i32 || local.y >= self.rect.size.h as i32 {
+			return 0.;
+		}
+		self.coverage[local.y as usize * self.rect.size.w as usize + local.x as usize].min(1.)
+	}
+
+	pub fn contains(&self, p: PointF) -> bool {
+		self.coverage(Point { x: p.x.floor() as i32, y: p.y.floor() as i32 }) > 0.
+	}
+
+	/// Mask of `rect` for the selection texture, the coverage in every channel.
+	pub fn mask(&self) -> Vec<u8> {
+		self.coverage.iter().flat_map(|c| [(c.min(1.) * 255.).round() as u8; 4]).collect()
+	}
+
+	/// The selection moved by `offset`, `None` if nothing of it is left inside the canvas.
+	pub fn moved(&self, offset: Point, canvas: Size) -> Option<Selection> {
+		let mut outline = self.outline.clone();
+		outline.pop();
+		for p in outline.iter_mut() {
+			*p += offset.into();
+		}
+		Selection::new(outline, canvas)
+	}
+}
+
+/// Moves the selected pixels of `pixels`, Rgba8 rows of `area`, by `offset`. They're left in place too if `duplicate`.
+/// Parts moved outside of `area` are dropped.
+pub fn move_pixels(pixels: &mut [u8], area: Rect, selection: &Selection, offset: Point, duplicate: bool) {
+	let w = area.size.w as usize;
+	let index = |p: Point| {
+		let local = p - area.pos;
+		let inside = local.x >= 0 && local.y >= 0 && local.x < area.size.w as i32 && local.y < area.size.h as i32;
+		inside.then(|| local.y as usize * w + local.x as usize)
+	};
+
+	// Lifted first, the source and destination can overlap
+	let src = selection.rect();
+	let mut floating = Vec::with_capacity((src.size.w * src.size.h) as usize);
+	for y in src.pos.y..src.pos.y + src.size.h as i32 {
+		for x in src.pos.x..src.pos.x + src.size.w as i32 {
+			let p = Point { x, y };
+			let coverage = selection.coverage(p);
+			let i = match index(p) {
+				Some(i) if coverage > 0. => i,
+				_ => continue,
+			};
+
+			let px = &mut pixels[4 * i..4 * i + 4];
+			let mut lifted = [px[0], px[1], px[2], px[3]];
+			lifted[3] = (lifted[3] as f32 * coverage).round() as u8;
+			if !duplicate {
+				px[3] = (px[3] as f32 * (1. - coverage)).round() as u8;
+			}
+			floating.push((p + offset, lifted));
+		}
+	}
+
+	for (p, src) in floating {
+		if let Some(i) = index(p) {
+			let color = [0, 1, 2].map(|c| src[c] as f32 / 255.);
+			let blended = source_over(&pixels[4 * i..4 * i + 4], color, src[3] as f32 / 255.);
+			pixels[4 * i..4 * i + 4].copy_from_slice(&blended);
+		}
+	}
+}
+
+/// Makes the selected pixels of `pixels`, Rgba8 rows of `area`, transparent.
+pub fn erase_pixels(pixels: &mut [u8], area: Rect, selection: &Selection) {
+	for (i, px) in pixels.chunks_exact_mut(4).enumerate() {
+		let p = area.pos + Point { x: (i % area.size.w as usize) as i32, y: (i / area.size.w as usize) as i32 };
+		px[3] = (px[3] as f32 * (1. - selection.coverage(p))).round() as u8;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CANVAS: Size = Size { w: 8, h: 8 };
+
+	fn square(x: f32, y: f32, side: f32) -> Vec<PointF> {
+		vec![PointF { x, y }, PointF { x: x + side, y }, PointF { x: x + side, y: y + side }, PointF { x, y: y + side }]
+	}
+
+	/// Opaque pixels of `area` with their index as red, to tell where each one ends up.
+	fn numbered(area: Rect) -> Vec<u8> {
+		(0..area.size.w * area.size.h).flat_map(|i| [i as u8, 0, 0, 255]).collect()
+	}
+
+	fn pixel(pixels: &[u8], area: Rect, x: i32, y: i32) -> [u8; 4] {
+		let i = 4 * ((y - area.pos.y) as usize * area.size.w as usize + (x - area.pos.x) as usize);
+		[pixels[i], pixels[i + 1], pixels[i + 2], pixels[i + 3]]
+	}
+
+	#[test]
+	fn new_covers_the_outline() {
+		let selection = Selection::new(square(1.5, 2., 3.), CANVAS).unwrap();
+		assert_eq!(selection.rect(), Rect::new(1, 2, 4, 3));
+		assert_eq!(selection.outline().len(), 5);
+		assert_eq!(selection.outline()[0], selection.outline()[4]);
+
+		assert_eq!(selection.coverage(Point { x: 1, y: 2 }), 0.5);
+		assert_eq!(selection.coverage(Point { x: 2, y: 3 }), 1.);
+		assert_eq!(selection.coverage(Point { x: 4, y: 4 }), 0.5);
+		assert_eq!(selection.coverage(Point { x: 5, y: 3 }), 0.);
+		assert_eq!(selection.coverage(Point { x: 2, y: 5 }), 0.);
+		assert!(selection.contains(PointF { x: 3.5, y: 4.5 }));
+		assert!(!selection.contains(PointF { x: 3.5, y: 5.5 }));
+
+		// Clipped to the canvas
+		let clipped = Selection::new(square(6., -2., 4.), CANVAS).unwrap();
+		assert_eq!(clipped.rect(), Rect::new(6, 0, 2, 2));
+	}
+
+	#[test]
+	fn new_rejects_empty_outlines() {
+		assert!(Selection::new(vec![PointF { x: 1., y: 1. }, PointF { x: 4., y: 4. }], CANVAS).is_none());
+		assert!(Selection::new(square(10., 10., 3.), CANVAS).is_none());
+		let line = vec![PointF { x: 1., y: 1. }, PointF { x: 3., y: 3. }, PointF { x: 5., y: 5. }];
+		assert!(Selection::new(line, CANVAS).is_none());
+	}
+
+	#[test]
+	fn move_pixels_overlapping_destination() {
+		let area = Rect::new(0, 0, 8, 8);
+		let selection = Selection::new(square(1., 1., 3.), CANVAS).unwrap();
+		let original = numbered(area);
+		let mut pixels = original.clone();
+		move_pixels(&mut pixels, area, &selection, Point { x: 1, y: 1 }, false);
+
+		for y in 0..8 {
+			for x in 0..8 {
+				let moved_from = (x - 1, y - 1);
+				let px = pixel(&pixels, area, x, y);
+				if (1..4).contains(&moved_from.0) && (1..4).contains(&moved_from.1) {
+					// Read from the source before it was overwritten
+					assert_eq!(px, pixel(&original, area, moved_from.0, moved_from.1), "{x}, {y}");
+				} else if (1..4).contains(&x) && (1..4).contains(&y) {
+					assert_eq!(px[3], 0, "{x}, {y} should be left transparent");
+				} else {
+					assert_eq!(px, pixel(&original, area, x, y), "{x}, {y} should be untouched");
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn move_pixels_duplicate_keeps_the_source() {
+		let area = Rect::new(0, 0, 8, 8);
+		let selection = Selection::new(square(1., 1., 2.), CANVAS).unwrap();
+		let original = numbered(area);
+		let mut pixels = original.clone();
+		move_pixels(&mut pixels, area, &selection, Point { x: 4, y: 3 }, true);
+
+		for y in 0..8 {
+			for x in 0..8 {
+				let px = pixel(&pixels, area, x, y);
+				if (5..7).contains(&x) && (4..6).contains(&y) {
+					assert_eq!(px, pixel(&original, area, x - 4, y - 3), "{x}, {y}");
+				} else {
+					assert_eq!(px, pixel(&original, area, x, y), "{x}, {y} should be untouched");
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn move_pixels_drops_what_leaves_the_area() {
+		let area = Rect::new(2, 2, 4, 4);
+		let selection = Selection::new(square(2., 2., 2.), CANVAS).unwrap();
+		let original = numbered(area);
+		let mut pixels = original.clone();
+		move_pixels(&mut pixels, area, &selection, Point { x: 3, y: 0 }, false);
+
+		assert_eq!(pixel(&pixels, area, 5, 2), pixel(&original, area, 2, 2));
+		assert_eq!(pixel(&pixels, area, 5, 3), pixel(&original, area, 2, 3));
+		for (x, y) in [(2, 2), (3, 2), (2, 3), (3, 3)] {
+			assert_eq!(pixel(&pixels, area, x, y)[3], 0);
+		}
+	}
+
+	#[test]
+	fn erase_pixels_scales_alpha_by_coverage() {
+		let area = Rect::new(1, 1, 4, 2);
+		let selection = Selection::new(square(1.5, 1., 2.), CANVAS).unwrap();
+		let mut pixels = numbered(area);
+		erase_pixels(&mut pixels, area, &selection);
+
+		let alphas: Vec<u8> = pixels.chunks_exact(4).map(|p| p[3]).collect();
+		// Half covered pixels keep half their alpha, rounded
+		assert_eq!(alphas, [128, 0, 128, 255, 128, 0, 128, 255]);
+		assert!(pixels.chunks_exact(4).enumerate().all(|(i, p)| p[0] == i as u8));
+	}
+}
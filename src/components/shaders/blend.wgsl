@@ -0,0 +1,53 @@
+// Color helpers shared by the canvas and composite shaders, prepended to them when the modules are created
+
+fn srgb_to_linear(c: vec3<f32>) -> vec3<f32> {
+	let low = c / 12.92;
+	let high = pow((c + 0.055) / 1.055, vec3<f32>(2.4));
+	return select(high, low, c <= vec3<f32>(0.04045));
+}
+
+fn linear_to_srgb(c: vec3<f32>) -> vec3<f32> {
+	let low = c * 12.92;
+	let high = 1.055 * pow(c, vec3<f32>(1. / 2.4)) - 0.055;
+	return select(high, low, c <= vec3<f32>(0.0031308));
+}
+
+// Separable blend modes, b is the backdrop and s the source. Values match BlendMode in canvas.rs
+fn blend(mode: u32, b: vec3<f32>, s: vec3<f32>) -> vec3<f32> {
+	switch mode {
+		// Multiply
+		case 1u: {
+			return b * s;
+		}
+		// Screen
+		case 2u: {
+			return b + s - b * s;
+		}
+		// Overlay
+		case 3u: {
+			return select(1. - 2. * (1. - b) * (1. - s), 2. * b * s, b <= vec3<f32>(0.5));
+		}
+		// Darken
+		case 4u: {
+			return min(b, s);
+		}
+		// Lighten
+		case 5u: {
+			return max(b, s);
+		}
+		default: {
+			return s;
+		}
+	}
+}
+
+// Non premultiplied "source over" compositing
+fn source_over(dst: vec4<f32>, src: vec3<f32>, src_alpha: f32) -> vec4<f32> {
+	let alpha = src_alpha + dst.a * (1. - src_alpha);
+	if alpha == 0. {
+		return vec4<f32>(0., 0., 0., 0.);
+	}
+
+	let color = (src * src_alpha + dst.rgb * dst.a * (1. - src_alpha)) / alpha;
+	return vec4<f32>(color, alpha);
+}
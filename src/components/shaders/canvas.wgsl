@@ -1,19 +1,25 @@
 @group(0) @binding(0)
 var tex: texture_storage_2d<rgba8unorm, read_write>;
 
-var<push_constant> clear_color: vec3<f32>;
+struct ClearInput {
+	color: vec4<f32>,
+	origin: vec2<i32>,
+	size: vec2<u32>,
+}
+
+var<push_constant> clear_in: ClearInput;
 
 @compute
 @workgroup_size(8, 8, 1)
 fn clear(@builtin(global_invocation_id) gid: vec3<u32>) {
-	let pos = vec2<i32>(gid.xy);
+	let pos = vec2<i32>(gid.xy) + clear_in.origin;
 	let dims = textureDimensions(tex);
 
-	if pos.x > dims.x || pos.y > dims.y {
+	if gid.x >= clear_in.size.x || gid.y >= clear_in.size.y || pos.x > dims.x || pos.y > dims.y {
 		return;
 	}
 
-	textureStore(tex, pos, vec4<f32>(clear_color, 1.));
+	textureStore(tex, pos, clear_in.color);
 }
 
 fn inside_circle(center: vec2<f32>, radius: f32, p: vec2<f32>) -> bool {
@@ -22,7 +28,8 @@ fn inside_circle(center: vec2<f32>, radius: f32, p: vec2<f32>) -> bool {
 
 struct DrawInput {
 	mouse: vec2<i32>,
-	brush_rad: u32
+	brush_rad: u32,
+	color: vec4<f32>,
 }
 var<push_constant> point_in: DrawInput;
 
@@ -36,8 +43,7 @@ fn draw_point(@builtin(global_invocation_id) gid: vec3<u32>) {
 	}
 
 	if inside_circle(vec2<f32>(point_in.mouse), f32(point_in.brush_rad), vec2<f32>(pos)) {
-		let color = vec4<f32>(1., 1., 1., 1.);
-		textureStore(tex, pos, color);
+		textureStore(tex, pos, point_in.color);
 	}
 }
 
@@ -48,58 +54,138 @@ fn scalar_projection(a: vec2<f32>, b: vec2<f32>, c: vec2<f32>) -> f32 {
 	return dot(v1, v2) / length(v1);
 }
 
-fn scalar_rejection(a: vec2<f32>, b: vec2<f32>, c: vec2<f32>) -> f32 {
-	let v1 = b - a;
-	let v2 = c - a;
+// Position of the point of segment ab closest to p, as a fraction of the segment
+fn segment_t(a: vec2<f32>, b: vec2<f32>, p: vec2<f32>) -> f32 {
+	let len = length(b - a);
+	if len == 0. {
+		return 0.;
+	}
+
+	return clamp(scalar_projection(a, b, p) / len, 0., 1.);
+}
 
-	return (v1.y * v2.x - v1.x * v2.y) / length(v1);
+struct LineInput {
+	tile_start: u32,
+	tile_count: u32,
+
+	color: vec4<f32>,
+	antialias: u32,
+	blend_mode: u32,
+	linear: u32,
+	erase: u32,
 }
 
-fn inside_line(a: vec2<f32>, b: vec2<f32>, width: f32, p: vec2<f32>) -> bool {
-	let proj = scalar_projection(a, b, p);
-	let rej = scalar_rejection(a, b, p);
+var<push_constant> line_in: LineInput;
 
-	return abs(rej) <= width && proj >= 0. && proj <= length(b - a);
+struct LinePoint {
+	// Texel t spans t..t+1
+	pos: vec2<f32>,
+	radius: f32,
+	opacity: f32,
 }
 
-struct LineInput {
-	reference_point: vec2<i32>,
-	line_start_index: u32,
-	line_end_index: u32,
+@group(1) @binding(0)
+var<storage, read> points: array<LinePoint>;
 
-	brush_rad: u32,
+// 8x8 pixel area and the segments that may touch it
+struct Tile {
+	origin: vec2<i32>,
+	segment_start: u32,
+	segment_count: u32,
 }
 
-var<push_constant> line_in: LineInput;
+@group(1) @binding(1)
+var<storage, read> tiles: array<Tile>;
 
-@group(1) @binding(0)
-var<storage, read> points: array<vec2<i32>>;
+// Index in `points` of the first point of each segment, grouped by tile
+@group(1) @binding(2)
+var<storage, read> segments: array<u32>;
+
+// Canvas content before the current stroke
+@group(0) @binding(1)
+var base: texture_storage_2d<rgba8unorm, read>;
+
+// Coverage of the current stroke in the red channel
+@group(0) @binding(2)
+var mask: texture_storage_2d<rgba8unorm, read_write>;
 
+// Selected fraction of each pixel in the red channel, everything is selected when there's no selection
+@group(0) @binding(3)
+var selection: texture_storage_2d<rgba8unorm, read>;
+
+// Each workgroup draws a tile, so pixels are only tested against the segments near them
 @compute
 @workgroup_size(8, 8, 1)
-fn draw_line(@builtin(global_invocation_id) gid: vec3<u32>) {
-	let pos = vec2<i32>(gid.xy) + line_in.reference_point;
-	let dims = textureDimensions(tex);
-	if 0 > pos.x || pos.x > dims.x || 0 > pos.y || pos.y > dims.y {
+fn draw_line(
+	@builtin(workgroup_id) wid: vec3<u32>,
+	@builtin(num_workgroups) nwg: vec3<u32>,
+	@builtin(local_invocation_id) lid: vec3<u32>,
+) {
+	let tile_index = wid.y * nwg.x + wid.x;
+	if tile_index >= line_in.tile_count {
 		return;
 	}
+	let tile = tiles[line_in.tile_start + tile_index];
 
-	let r = f32(line_in.brush_rad);
-
-	var i = line_in.line_start_index;
-	var flag = false;
-	while i < line_in.line_end_index - u32(1) {
-		let a = vec2<f32>(points[i]);
-		let b = vec2<f32>(points[i+u32(1)]);
-		flag = inside_circle(a, r, vec2<f32>(pos)) ||
-				inside_line(a, b, r, vec2<f32>(pos)) ||
-				inside_circle(b, r, vec2<f32>(pos));
-		if flag { break; }
-		i = i + u32(1);
+	let pos = tile.origin + vec2<i32>(lid.xy);
+	let dims = textureDimensions(tex);
+	if pos.x >= dims.x || pos.y >= dims.y {
+		return;
 	}
 
-	if flag {
-		let color = vec4<f32>(1., 1., 1., 1.);
+	// Texel center
+	let p = vec2<f32>(pos) + 0.5;
+
+	// Strongest coverage among the segments touching this pixel
+	var alpha = 0.;
+	for (var k = tile.segment_start; k < tile.segment_start + tile.segment_count; k = k + u32(1)) {
+		let i = segments[k];
+		let a = points[i];
+		let b = points[i+u32(1)];
+		let pa = a.pos;
+		let pb = b.pos;
+		let t = segment_t(pa, pb, p);
+
+		// Signed distance to the edge of the capsule, negative inside
+		let dist = distance(mix(pa, pb, t), p) - mix(a.radius, b.radius, t);
+
+		var coverage = 0.;
+		if line_in.antialias != u32(0) {
+			coverage = 1. - smoothstep(-0.5, 0.5, dist);
+		} else if dist <= 0. {
+			coverage = 1.;
+		}
+
+		alpha = max(alpha, coverage * mix(a.opacity, b.opacity, t));
+	}
+	alpha = alpha * textureLoad(selection, pos).r;
+
+	// The stroke is composited over the base using its highest coverage so far,
+	// so overlapping segments don't accumulate opacity
+	let coverage = textureLoad(mask, pos).r;
+	if alpha > coverage {
+		textureStore(mask, pos, vec4<f32>(alpha, 0., 0., 0.));
+
+		if line_in.erase != u32(0) {
+			let dst = textureLoad(base, pos);
+			textureStore(tex, pos, vec4<f32>(dst.rgb, dst.a * (1. - alpha * line_in.color.a)));
+			return;
+		}
+
+		var dst = textureLoad(base, pos);
+		var src = line_in.color.rgb;
+		if line_in.linear != u32(0) {
+			dst = vec4<f32>(srgb_to_linear(dst.rgb), dst.a);
+			src = srgb_to_linear(src);
+		}
+
+		// Where the backdrop is transparent the source is used as is
+		let blended = mix(src, blend(line_in.blend_mode, dst.rgb, src), dst.a);
+		var color = source_over(dst, blended, alpha * line_in.color.a);
+
+		if line_in.linear != u32(0) {
+			color = vec4<f32>(linear_to_srgb(color.rgb), color.a);
+		}
 		textureStore(tex, pos, color);
 	}
 }
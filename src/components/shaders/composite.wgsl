@@ -0,0 +1,62 @@
+struct VertexOutput {
+	@builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+	@builtin(vertex_index) index: u32,
+) -> VertexOutput {
+	var out: VertexOutput;
+
+	var pos = vec2<f32>(0., 0.);
+
+	if index % u32(2) == u32(1) {
+		pos.y = 1.;
+	}
+
+	if index == u32(0) || index >= u32(4) {
+		pos.x = 1.;
+	}
+
+	out.clip_position = vec4<f32>(2. * pos - 1., 1., 1.);
+
+	return out;
+}
+
+// Fragment shader
+
+@group(0) @binding(0)
+var layer: texture_storage_2d<rgba8unorm, read>;
+
+// Result of compositing the layers below
+@group(0) @binding(1)
+var backdrop: texture_storage_2d<rgba8unorm, read>;
+
+struct LayerInput {
+	opacity: f32,
+	blend_mode: u32,
+	linear: u32,
+}
+
+var<push_constant> layer_in: LayerInput;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+	let pos = vec2<i32>(floor(in.clip_position.xy));
+
+	var dst = textureLoad(backdrop, pos);
+	let src = textureLoad(layer, pos);
+	var src_color = src.rgb;
+	if layer_in.linear != u32(0) {
+		dst = vec4<f32>(srgb_to_linear(dst.rgb), dst.a);
+		src_color = srgb_to_linear(src_color);
+	}
+
+	let blended = mix(src_color, blend(layer_in.blend_mode, dst.rgb, src_color), dst.a);
+	var color = source_over(dst, blended, src.a * layer_in.opacity);
+
+	if layer_in.linear != u32(0) {
+		color = vec4<f32>(linear_to_srgb(color.rgb), color.a);
+	}
+	return color;
+}
@@ -30,9 +30,20 @@ fn vs_main(
 @binding(0)
 var tex: texture_storage_2d<rgba8unorm, read>;
 
+struct ViewInput {
+	// Framebuffer position of the texture's top-left corner
+	origin: vec2<f32>,
+	scale: f32,
+}
+
+var<push_constant> view: ViewInput;
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
 	let dim = textureDimensions(tex);
-	var pos = vec2<i32>(i32(in.pos.x * f32(dim.x)), i32((1. - in.pos.y) * f32(dim.y)));
-	return textureLoad(tex, pos);
+	let pos = (in.clip_position.xy - view.origin) / view.scale;
+	if pos.x < 0. || pos.y < 0. || pos.x >= f32(dim.x) || pos.y >= f32(dim.y) {
+		return vec4<f32>(0., 0., 0., 0.);
+	}
+	return textureLoad(tex, vec2<i32>(floor(pos)));
 }
@@ -0,0 +1,94 @@
+// Line kernel from before strokes were binned into tiles, only used to benchmark against the tiled one.
+// Every pixel of the stroke's bounding box is tested against all of its segments.
+
+@group(0) @binding(0)
+var tex: texture_storage_2d<rgba8unorm, read_write>;
+
+@group(0) @binding(1)
+var base: texture_storage_2d<rgba8unorm, read>;
+
+@group(0) @binding(2)
+var mask: texture_storage_2d<rgba8unorm, read_write>;
+
+struct LinePoint {
+	pos: vec2<f32>,
+	radius: f32,
+	opacity: f32,
+}
+
+@group(1) @binding(0)
+var<storage, read> points: array<LinePoint>;
+
+struct LineInput {
+	reference_point: vec2<i32>,
+	line_start_index: u32,
+	line_end_index: u32,
+
+	color: vec4<f32>,
+	antialias: u32,
+	blend_mode: u32,
+	linear: u32,
+	erase: u32,
+}
+
+var<push_constant> line_in: LineInput;
+
+fn segment_t(a: vec2<f32>, b: vec2<f32>, p: vec2<f32>) -> f32 {
+	let len = length(b - a);
+	if len == 0. {
+		return 0.;
+	}
+
+	return clamp(dot(b - a, p - a) / (len * len), 0., 1.);
+}
+
+@compute
+@workgroup_size(8, 8, 1)
+fn draw_line(@builtin(global_invocation_id) gid: vec3<u32>) {
+	let pos = vec2<i32>(gid.xy) + line_in.reference_point;
+	let dims = textureDimensions(tex);
+	if 0 > pos.x || pos.x >= dims.x || 0 > pos.y || pos.y >= dims.y {
+		return;
+	}
+
+	let p = vec2<f32>(pos) + 0.5;
+
+	var alpha = 0.;
+	var i = line_in.line_start_index;
+	while i < line_in.line_end_index - u32(1) {
+		let a = points[i];
+		let b = points[i+u32(1)];
+		let t = segment_t(a.pos, b.pos, p);
+		let dist = distance(mix(a.pos, b.pos, t), p) - mix(a.radius, b.radius, t);
+
+		var coverage = 0.;
+		if line_in.antialias != u32(0) {
+			coverage = 1. - smoothstep(-0.5, 0.5, dist);
+		} else if dist <= 0. {
+			coverage = 1.;
+		}
+
+		alpha = max(alpha, coverage * mix(a.opacity, b.opacity, t));
+		i = i + u32(1);
+	}
+
+	let coverage = textureLoad(mask, pos).r;
+	if alpha > coverage {
+		textureStore(mask, pos, vec4<f32>(alpha, 0., 0., 0.));
+
+		var dst = textureLoad(base, pos);
+		var src = line_in.color.rgb;
+		if line_in.linear != u32(0) {
+			dst = vec4<f32>(srgb_to_linear(dst.rgb), dst.a);
+			src = srgb_to_linear(src);
+		}
+
+		let blended = mix(src, blend(line_in.blend_mode, dst.rgb, src), dst.a);
+		var color = source_over(dst, blended, alpha * line_in.color.a);
+
+		if line_in.linear != u32(0) {
+			color = vec4<f32>(linear_to_srgb(color.rgb), color.a);
+		}
+		textureStore(tex, pos, color);
+	}
+}
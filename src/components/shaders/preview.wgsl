@@ -0,0 +1,88 @@
+struct VertexOutput {
+	@builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+	@builtin(vertex_index) index: u32,
+) -> VertexOutput {
+	var out: VertexOutput;
+
+	var pos = vec2<f32>(0., 0.);
+
+	if index % u32(2) == u32(1) {
+		pos.y = 1.;
+	}
+
+	if index == u32(0) || index >= u32(4) {
+		pos.x = 1.;
+	}
+
+	out.clip_position = vec4<f32>(2. * pos - 1., 1., 1.);
+
+	return out;
+}
+
+// Fragment shader
+
+// Outline of the shape in texture coordinates, closed shapes repeat the first vertex at the end
+@group(0) @binding(0)
+var<storage, read> vertices: array<vec2<f32>>;
+
+struct PreviewInput {
+	// Framebuffer position of the texture's top-left corner
+	origin: vec2<f32>,
+	scale: f32,
+	radius: f32,
+	color: vec4<f32>,
+	count: u32,
+	filled: u32,
+	// Length of the dashes in framebuffer pixels, solid if 0
+	dash: f32,
+	phase: f32,
+}
+
+var<push_constant> preview: PreviewInput;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+	let p = (in.clip_position.xy - preview.origin) / preview.scale;
+
+	var d = 1e9;
+	var inside = false;
+	// Distance along the outline of its closest point
+	var total = 0.;
+	var along = 0.;
+	for (var i = u32(1); i < preview.count; i = i + u32(1)) {
+		let a = vertices[i - u32(1)];
+		let b = vertices[i];
+		let ab = b - a;
+		let t = clamp(dot(p - a, ab) / max(dot(ab, ab), 0.0001), 0., 1.);
+		let segment_d = distance(p, a + t * ab);
+		if segment_d < d {
+			d = segment_d;
+			along = total + t * distance(a, b);
+		}
+		total = total + distance(a, b);
+		// Even-odd rule, like the fill done on commit
+		if (a.y <= p.y) != (b.y <= p.y) && p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+			inside = !inside;
+		}
+	}
+
+	if preview.dash > 0. {
+		let white = fract((along * preview.scale + preview.phase) / (2. * preview.dash)) < 0.5;
+		let coverage = clamp(1. - d * preview.scale, 0., 1.);
+		return vec4<f32>(select(vec3<f32>(0., 0., 0.), vec3<f32>(1., 1., 1.), white), coverage);
+	}
+
+	// Edges are smoothed over a framebuffer pixel
+	var coverage: f32;
+	if preview.filled == u32(1) {
+		coverage = clamp(select(0.5 - d * preview.scale, 0.5 + d * preview.scale, inside), 0., 1.);
+	} else {
+		coverage = clamp((preview.radius - d) * preview.scale + 0.5, 0., 1.);
+	}
+
+	return vec4<f32>(preview.color.rgb, preview.color.a * coverage);
+}
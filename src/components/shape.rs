@@ -0,0 +1,347 @@
+use crate::components::{Point, PointF, Rect, Size};
+
+/// Maximum length of the segments shape outlines are drawn with, so their tiles stay close to the outline.
+const SEGMENT_LENGTH: f32 = 16.;
+/// Approximate length of the segments ellipses are made of
+const ELLIPSE_SEGMENT_LENGTH: f32 = 4.;
+const MIN_ELLIPSE_SEGMENTS: usize = 16;
+const MAX_ELLIPSE_SEGMENTS: usize = 256;
+/// Rows sampled per pixel when filling polygons, columns are covered exactly.
+const COVERAGE_ROWS: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShapeKind {
+	Line,
+	Rectangle,
+	Ellipse,
+	/// Vertices are added on each release, the shape is finished explicitly.
+	Polygon,
+}
+
+impl ShapeKind {
+	/// Cycles through all shapes, `None` being freehand drawing.
+	pub fn next(kind: Option<ShapeKind>) -> Option<ShapeKind> {
+		use ShapeKind::*;
+		match kind {
+			None => Some(Line),
+			Some(Line) => Some(Rectangle),
+			Some(Rectangle) => Some(Ellipse),
+			Some(Ellipse) => Some(Polygon),
+			Some(Polygon) => None,
+		}
+	}
+}
+
+/// A shape being drawn, in texture coordinates.
+pub struct Shape {
+	pub kind: ShapeKind,
+	/// Where it was started and, for polygons, every vertex added since
+	anchors: Vec<PointF>,
+	/// Current pointer position
+	pub cursor: PointF,
+}
+
+impl Shape {
+	pub fn new(kind: ShapeKind, p: PointF) -> Self {
+		Shape { kind, anchors: vec![p], cursor: p }
+	}
+
+	/// Adds the pointer position as a polygon vertex.
+	pub fn add_vertex(&mut self, constrain: bool) {
+		let p = self.end(constrain);
+		if self.anchors.last() != Some(&p) {
+			self.anchors.push(p);
+		}
+	}
+
+	/// Pointer position, with `constrain` snapped to 45° steps from the last anchor for lines and polygons,
+	/// or to squares and circles.
+	fn end(&self, constrain: bool) -> PointF {
+		let start = *self.anchors.last().unwrap();
+		let d = self.cursor - start;
+		if !constrain {
+			return self.cursor;
+		}
+
+		match self.kind {
+			ShapeKind::Line | ShapeKind::Polygon => {
+				let step = std::f32::consts::FRAC_PI_4;
+				let angle = (d.y.atan2(d.x) / step).round() * step;
+				let length = d.x.hypot(d.y);
+				PointF { x: start.x + length * angle.cos(), y: start.y + length * angle.sin() }
+			}
+			ShapeKind::Rectangle | ShapeKind::Ellipse => {
+				let side = d.x.abs().max(d.y.abs());
+				PointF { x: start.x + side.copysign(d.x), y: start.y + side.copysign(d.y) }
+			}
+		}
+	}
+
+	/// Whether there's anything to draw when finished.
+	pub fn is_empty(&self) -> bool {
+		match self.kind {
+			ShapeKind::Polygon => self.anchors.len() < 2,
+			_ => false,
+		}
+	}
+
+	/// Whether it encloses an area that can be filled.
+	pub fn is_closed(&self) -> bool {
+		self.kind != ShapeKind::Line
+	}
+
+	/// Vertices of the outline, closed ones repeat the first vertex at the end.
+	/// Polygons in progress include the pointer position as their last vertex.
+	pub fn outline(&self, constrain: bool, in_progress: bool) -> Vec<PointF> {
+		let start = self.anchors[0];
+		let end = self.end(constrain);
+		let mut vertices = match self.kind {
+			ShapeKind::Line => return vec![start, end],
+			ShapeKind::Rectangle => vec![
+				start,
+				PointF { x: end.x, y: start.y },
+				end,
+				PointF { x: start.x, y: end.y },
+			],
+			ShapeKind::Ellipse => {
+				let center = PointF { x: (start.x + end.x) / 2., y: (start.y + end.y) / 2. };
+				let (rx, ry) = ((end.x - start.x).abs() / 2., (end.y - start.y).abs() / 2.);
+				let perimeter = std::f32::consts::TAU * rx.max(ry);
+				let n = ((perimeter / ELLIPSE_SEGMENT_LENGTH) as usize).clamp(MIN_ELLIPSE_SEGMENTS, MAX_ELLIPSE_SEGMENTS);
+				(0..n).map(|i| {
+					let angle = std::f32::consts::TAU * i as f32 / n as f32;
+					PointF { x: center.x + rx * angle.cos(), y: center.y + ry * angle.sin() }
+				}).collect()
+			}
+			ShapeKind::Polygon => {
+				let mut vertices = self.anchors.clone();
+				if in_progress && vertices.last() != Some(&end) {
+					vertices.push(end);
+				}
+				vertices
+			}
+		};
+		vertices.push(vertices[0]);
+		vertices
+	}
+}
+
+/// Splits the segments of `points` so none is longer than `SEGMENT_LENGTH`.
+pub fn subdivide(points: &[PointF]) -> Vec<PointF> {
+	let mut out = vec![points[0]];
+	for pair in points.windows(2) {
+		let (a, b) = (pair[0], pair[1]);
+		let d = b - a;
+		let steps = (d.x.hypot(d.y) / SEGMENT_LENGTH).ceil().max(1.) as usize;
+		for i in 1..=steps {
+			let t = i as f32 / steps as f32;
+			out.push(PointF { x: a.x + d.x * t, y: a.y + d.y * t });
+		}
+	}
+	out
+}
+
+/// Drops the vertices of `points` within `tolerance` of the segment between the ones kept around them
+/// (Ramer-Douglas-Peucker). The first and last vertices are always kept.
+pub fn simplify(points: &[PointF], tolerance: f32) -> Vec<PointF> {
+	if points.len() < 3 {
+		return points.to_vec();
+	}
+
+	let mut keep = vec![false; points.len()];
+	keep[0] = true;
+	keep[points.len() - 1] = true;
+
+	let mut ranges = vec![(0, points.len() - 1)];
+	while let Some((first, last)) = ranges.pop() {
+		let (a, b) = (points[first], points[last]);
+		let ab = b - a;
+		let len2 = ab.x * ab.x + ab.y * ab.y;
+
+		let mut farthest = (0., first);
+		for (i, &p) in points.iter().enumerate().take(last).skip(first + 1) {
+			let t = if len2 == 0. { 0. } else { (((p.x - a.x) * ab.x + (p.y - a.y) * ab.y) / len2).clamp(0., 1.) };
+			let d = (p.x - a.x - ab.x * t).hypot(p.y - a.y - ab.y * t);
+			if d > farthest.0 {
+				farthest = (d, i);
+			}
+		}
+
+		if farthest.0 > tolerance {
+			keep[farthest.1] = true;
+			ranges.push((first, farthest.1));
+			ranges.push((farthest.1, last));
+		}
+	}
+
+	points.iter().zip(keep).filter(|(_, k)| *k).map(|(p, _)| *p).collect()
+}
+
+/// Pixels covered by the closed polygon `vertices`, clipped to `canvas`.
+pub fn bounds(vertices: &[PointF], canvas: Size) -> Option<Rect> {
+	let (mut min, mut max) = (vertices[0], vertices[0]);
+	for v in vertices {
+		min = PointF { x: min.x.min(v.x), y: min.y.min(v.y) };
+		max = PointF { x: max.x.max(v.x), y: max.y.max(v.y) };
+	}
+
+	let pos = Point { x: min.x.floor() as i32, y: min.y.floor() as i32 };
+	let size = Size { w: (max.x.ceil() as i32 - pos.x).max(1) as u32, h: (max.y.ceil() as i32 - pos.y).max(1) as u32 };
+	Rect { pos, size }.intersection(Rect { pos: Point { x: 0, y: 0 }, size: canvas })
+}
+
+/// Fraction of each pixel of `rect` inside the closed polygon `vertices` (even-odd rule), as rows.
+pub fn polygon_coverage(vertices: &[PointF], rect: Rect) -> Vec<f32> {
+	let (w, h) = (rect.size.w as usize, rect.size.h as usize);
+	let mut coverage = vec![0.; w * h];
+	let mut crossings = Vec::new();
+
+	for row in 0..h {
+		for sub in 0..COVERAGE_ROWS {
+			let y = rect.pos.y as f32 + row as f32 + (sub as f32 + 0.5) / COVERAGE_ROWS as f32;
+
+			crossings.clear();
+			for pair in vertices.windows(2) {
+				let (a, b) = (pair[0], pair[1]);
+				if (a.y <= y) != (b.y <= y) {
+					crossings.push(a.x + (y - a.y) / (b.y - a.y) * (b.x - a.x) - rect.pos.x as f32);
+				}
+			}
+			crossings.sort_by(f32::total_cmp);
+
+			for span in crossings.chunks_exact(2) {
+				let (x0, x1) = (span[0].max(0.), span[1].min(w as f32));
+				if x0 >= x1 {
+					continue;
+				}
+				for x in x0 as usize..(x1.ceil() as usize).min(w) {
+					let covered = x1.min(x as f32 + 1.) - x0.max(x as f32);
+					coverage[row * w + x] += covered / COVERAGE_ROWS as f32;
+				}
+			}
+		}
+	}
+	coverage
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn assert_near(a: PointF, b: PointF) {
+		assert!((a.x - b.x).abs() < 1e-4 && (a.y - b.y).abs() < 1e-4, "{a:?} != {b:?}");
+	}
+
+	#[test]
+	fn lines_snap_to_45_degrees() {
+		let mut shape = Shape::new(ShapeKind::Line, PointF { x: 10., y: 10. });
+		let length = |x: f32, y: f32| x.hypot(y);
+		let diagonal = |x: f32, y: f32| length(x, y) * std::f32::consts::FRAC_1_SQRT_2;
+
+		// The length is kept, the angle is rounded
+		for (d, snapped) in [
+			((10., 2.), (length(10., 2.), 0.)),
+			((7., 9.), (diagonal(7., 9.), diagonal(7., 9.))),
+			((1., -15.), (0., -length(1., -15.))),
+			((-12., 3.), (-length(-12., 3.), 0.)),
+			((-6., -5.), (-diagonal(-6., -5.), -diagonal(-6., -5.))),
+		] {
+			shape.cursor = PointF { x: 10. + d.0, y: 10. + d.1 };
+			assert_near(shape.end(true), PointF { x: 10. + snapped.0, y: 10. + snapped.1 });
+			assert_eq!(shape.end(false), shape.cursor);
+		}
+	}
+
+	#[test]
+	fn polygons_snap_from_the_last_vertex() {
+		let mut shape = Shape::new(ShapeKind::Polygon, PointF { x: 0., y: 0. });
+		shape.cursor = PointF { x: 20., y: 1. };
+		shape.add_vertex(true);
+		let vertex = PointF { x: 20f32.hypot(1.), y: 0. };
+		assert_near(shape.anchors[1], vertex);
+
+		shape.cursor = PointF { x: 21., y: 30. };
+		let d = shape.cursor - vertex;
+		assert_near(shape.end(true), PointF { x: vertex.x, y: d.x.hypot(d.y) });
+	}
+
+	#[test]
+	fn rectangles_snap_to_squares_and_ellipses_to_circles() {
+		let start = PointF { x: 10., y: 10. };
+		for kind in [ShapeKind::Rectangle, ShapeKind::Ellipse] {
+			let mut shape = Shape::new(kind, start);
+			// The longest side is kept in the direction of the pointer
+			shape.cursor = PointF { x: 4., y: 18. };
+			assert_eq!(shape.end(true), PointF { x: 2., y: 18. });
+			shape.cursor = PointF { x: 30., y: 5. };
+			assert_eq!(shape.end(true), PointF { x: 30., y: -10. });
+		}
+
+		let mut circle = Shape::new(ShapeKind::Ellipse, start);
+		circle.cursor = PointF { x: 40., y: 25. };
+		let center = PointF { x: 25., y: 25. };
+		for v in circle.outline(true, false) {
+			assert!(((v.x - center.x).hypot(v.y - center.y) - 15.).abs() < 1e-3, "{v:?}");
+		}
+	}
+
+	#[test]
+	fn simplify_keeps_corners_within_tolerance() {
+		// A closed square with a vertex every pixel and a little jitter
+		let mut points = Vec::new();
+		for (a, b) in [((0., 0.), (20., 0.)), ((20., 0.), (20., 20.)), ((20., 20.), (0., 20.)), ((0., 20.), (0., 0.))] {
+			for i in 0..20 {
+				let t = i as f32 / 20.;
+				let jitter = if i % 3 == 1 { 0.2 } else { 0. };
+				points.push(PointF { x: a.0 + (b.0 - a.0) * t + jitter, y: a.1 + (b.1 - a.1) * t + jitter });
+			}
+		}
+		points.push(points[0]);
+
+		let simplified = simplify(&points, 0.5);
+		assert_eq!(simplified, [
+			PointF { x: 0., y: 0. },
+			PointF { x: 20., y: 0. },
+			PointF { x: 20., y: 20. },
+			PointF { x: 0., y: 20. },
+			PointF { x: 0., y: 0. },
+		]);
+
+		// Nothing is dropped without tolerance for the jitter
+		assert!(simplify(&points, 0.1).len() > 40);
+		assert_eq!(simplify(&points[..2], 10.), points[..2]);
+	}
+
+	#[test]
+	fn rectangle_coverage_is_exact() {
+		let canvas = Size { w: 16, h: 16 };
+		// Expected coverage of each pixel
+		type Coverage = fn(i32, i32) -> f32;
+		let cases: [(PointF, PointF, Coverage); 2] = [
+			// Aligned to pixels, so fully in or out
+			(PointF { x: 2., y: 3. }, PointF { x: 7., y: 9. }, |x: i32, y: i32| ((2..7).contains(&x) && (3..9).contains(&y)) as u8 as f32),
+			// Half and quarter pixels at the edges
+			(PointF { x: 7.5, y: 9.25 }, PointF { x: 2.5, y: 3. }, |x: i32, y: i32| {
+				let column = match x { 2 | 7 => 0.5, 3..=6 => 1., _ => 0. };
+				let row = match y { 9 => 0.25, 3..=8 => 1., _ => 0. };
+				column * row
+			}),
+		];
+		for (start, end, expected) in cases {
+			let mut shape = Shape::new(ShapeKind::Rectangle, start);
+			shape.cursor = end;
+			let outline = shape.outline(false, false);
+
+			let rect = bounds(&outline, canvas).unwrap();
+			assert_eq!(rect.pos, Point { x: start.x.min(end.x).floor() as i32, y: start.y.min(end.y).floor() as i32 });
+
+			// A pixel of margin around the bounds
+			let around = Rect::new(rect.pos.x - 1, rect.pos.y - 1, rect.size.w + 2, rect.size.h + 2);
+			let coverage = polygon_coverage(&outline, around);
+			for (i, c) in coverage.iter().enumerate() {
+				let (x, y) = (around.pos.x + (i % around.size.w as usize) as i32, around.pos.y + (i / around.size.w as usize) as i32);
+				assert_eq!(*c, expected(x, y), "Pixel {x}, {y}");
+			}
+		}
+	}
+}
@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+
+use crate::components::PointF;
+
+const MOVING_AVERAGE_WINDOW: usize = 6;
+const STRING_LENGTH: f32 = 20.;
+/// Maximum distance between resampled points
+const SPACING: f32 = 2.;
+
+/// Pointer position, in texture coordinates, and its pressure.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sample {
+	pub pos: PointF,
+	pub pressure: f32,
+}
+
+impl Sample {
+	fn lerp(self, other: Sample, t: f32) -> Sample {
+		Sample {
+			pos: self.pos + (other.pos - self.pos) * t,
+			pressure: self.pressure + (other.pressure - self.pressure) * t,
+		}
+	}
+
+	fn distance(self, other: Sample) -> f32 {
+		let d = other.pos - self.pos;
+		d.x.hypot(d.y)
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StabilizerMode {
+	Off,
+	/// Average of the last `window` positions.
+	MovingAverage { window: usize },
+	/// The brush is pulled by a string attached to the pointer, it only moves once the string is tight.
+	LazyBrush { string_length: f32 },
+}
+
+impl StabilizerMode {
+	/// Cycles through all modes with their default settings.
+	pub fn next(self) -> StabilizerMode {
+		use StabilizerMode::*;
+		match self {
+			Off => MovingAverage { window: MOVING_AVERAGE_WINDOW },
+			MovingAverage { .. } => LazyBrush { string_length: STRING_LENGTH },
+			LazyBrush { .. } => Off,
+		}
+	}
+}
+
+/// Turns raw pointer samples into stroke points. Points are first stabilized according to `mode`
+/// and then, if `resample` is set, interpolated with a Catmull-Rom spline.
+pub struct Stabilizer {
+	pub mode: StabilizerMode,
+	pub resample: bool,
+	/// Raw samples averaged by `MovingAverage`
+	window: VecDeque<Sample>,
+	/// Brush position of `LazyBrush`
+	brush: Sample,
+	/// Last raw sample
+	last: Sample,
+	/// Spline control points, the segment between the second and third one is emitted when the fourth arrives
+	controls: VecDeque<Sample>,
+}
+
+impl Default for Stabilizer {
+	fn default() -> Self {
+		let origin = Sample { pos: PointF { x: 0., y: 0. }, pressure: 1. };
+		Stabilizer {
+			mode: StabilizerMode::Off,
+			resample: false,
+			window: VecDeque::new(),
+			brush: origin,
+			last: origin,
+			controls: VecDeque::new(),
+		}
+	}
+}
+
+impl Stabilizer {
+	/// Starts a stroke, returns its first point.
+	pub fn begin(&mut self, s: Sample) -> Sample {
+		self.window = VecDeque::from([s]);
+		self.brush = s;
+		self.last = s;
+		// The first point is its own previous control point
+		self.controls = VecDeque::from([s, s]);
+		s
+	}
+
+	/// Adds a pointer sample, returns the new stroke points.
+	pub fn push(&mut self, s: Sample) -> Vec<Sample> {
+		self.last = s;
+
+		let stabilized = match self.mode {
+			StabilizerMode::Off => Some(s),
+			StabilizerMode::MovingAverage { window } => {
+				self.window.push_back(s);
+				while self.window.len() > window.max(1) {
+					self.window.pop_front();
+				}
+				let n = self.window.len() as f32;
+				let sum = self.window.iter().fold(Sample { pos: PointF { x: 0., y: 0. }, pressure: 0. }, |acc, s| Sample {
+					pos: acc.pos + s.pos,
+					pressure: acc.pressure + s.pressure,
+				});
+				Some(Sample { pos: sum.pos * n.recip(), pressure: sum.pressure / n })
+			}
+			StabilizerMode::LazyBrush { string_length } => {
+				let d = self.brush.distance(s);
+				if d > string_length {
+					let pos = self.brush.lerp(s, (d - string_length) / d).pos;
+					self.brush = Sample { pos, pressure: s.pressure };
+					Some(self.brush)
+				} else {
+					None
+				}
+			}
+		};
+
+		let mut out = vec![];
+		if let Some(p) = stabilized {
+			self.emit(p, &mut out);
+		}
+		out
+	}
+
+	/// Ends the stroke at the last pointer position, returns the remaining points.
+	pub fn end(&mut self) -> Vec<Sample> {
+		let mut out = vec![];
+		self.emit(self.last, &mut out);
+		if self.resample {
+			// The last point is its own next control point
+			self.emit(self.last, &mut out);
+		}
+		out
+	}
+
+	fn emit(&mut self, p: Sample, out: &mut Vec<Sample>) {
+		if !self.resample {
+			out.push(p);
+			return;
+		}
+
+		self.controls.push_back(p);
+		if self.controls.len() < 4 {
+			return;
+		}
+
+		let [p0, p1, p2, p3] = [0, 1, 2, 3].map(|i| self.controls[i]);
+		let steps = (p1.distance(p2) / SPACING).ceil().max(1.) as usize;
+		for i in 1..=steps {
+			out.push(catmull_rom(p0, p1, p2, p3, i as f32 / steps as f32));
+		}
+		self.controls.pop_front();
+	}
+}
+
+/// Uniform Catmull-Rom spline between `p1` and `p2`, pressure is interpolated linearly.
+fn catmull_rom(p0: Sample, p1: Sample, p2: Sample, p3: Sample, t: f32) -> Sample {
+	let t2 = t * t;
+	let t3 = t2 * t;
+	let pressure = p1.pressure + (p2.pressure - p1.pressure) * t;
+	let [p0, p1, p2, p3] = [p0, p1, p2, p3].map(|p| p.pos);
+	let pos = (
+		p1 * 2.
+		+ (p2 - p0) * t
+		+ (p0 * 2. - p1 * 5. + p2 * 4. - p3) * t2
+		+ (p1 * 3. - p0 - p2 * 3. + p3) * t3
+	) * 0.5;
+	Sample { pos, pressure }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sample(x: f32, y: f32) -> Sample {
+		Sample { pos: PointF { x, y }, pressure: 1. }
+	}
+
+	/// Runs a whole stroke through `stabilizer`.
+	fn stroke(stabilizer: &mut Stabilizer, samples: &[Sample]) -> Vec<Sample> {
+		let mut out = vec![stabilizer.begin(samples[0])];
+		for &s in &samples[1..] {
+			out.extend(stabilizer.push(s));
+		}
+		out.extend(stabilizer.end());
+		out
+	}
+
+	/// Mean distance of the points to the line `y = 0`.
+	fn jitter(points: &[Sample]) -> f32 {
+		points.iter().map(|s| s.pos.y.abs()).sum::<f32>() / points.len() as f32
+	}
+
+	#[test]
+	fn moving_average_reduces_jitter() {
+		// A horizontal line with deterministic noise of ±2 pixels
+		let noisy: Vec<Sample> = (0..200)
+			.map(|i| {
+				let sign = if i % 2 == 0 { 1. } else { -1. };
+				sample(i as f32, sign * 2. * (i % 7) as f32 / 6.)
+			})
+			.collect();
+
+		let mut stabilizer = Stabilizer { mode: StabilizerMode::MovingAverage { window: MOVING_AVERAGE_WINDOW }, ..Stabilizer::default() };
+		let smoothed = stroke(&mut stabilizer, &noisy);
+		// Skips the start, where the window is filling up, and the end, which jumps to the pointer
+		let steady = &smoothed[MOVING_AVERAGE_WINDOW..smoothed.len() - 1];
+		assert!(jitter(steady) < jitter(&noisy) / 2., "{} vs {}", jitter(steady), jitter(&noisy));
+	}
+
+	#[test]
+	fn lazy_brush_stays_within_string_length() {
+		let mut stabilizer = Stabilizer { mode: StabilizerMode::LazyBrush { string_length: STRING_LENGTH }, ..Stabilizer::default() };
+		stabilizer.begin(sample(0., 0.));
+
+		// Wandering inside the string's reach doesn't move the brush
+		for &(x, y) in &[(5., 5.), (-10., 3.), (0., -STRING_LENGTH), (14., 14.)] {
+			assert!(stabilizer.push(sample(x, y)).is_empty());
+		}
+
+		// Past it, the brush is pulled to a string length behind the pointer
+		let out = stabilizer.push(sample(50., 0.));
+		assert_eq!(out.len(), 1);
+		assert!((out[0].pos.x - (50. - STRING_LENGTH)).abs() < 1e-4);
+		assert_eq!(out[0].pos.y, 0.);
+	}
+
+	#[test]
+	fn catmull_rom_passes_through_control_points() {
+		let [p0, p1, p2, p3] = [sample(0., 0.), sample(10., 5.), sample(20., -5.), sample(30., 0.)];
+		assert_eq!(catmull_rom(p0, p1, p2, p3, 0.), p1);
+		assert_eq!(catmull_rom(p0, p1, p2, p3, 1.), p2);
+	}
+
+	#[test]
+	fn resampled_stroke_hits_every_sample() {
+		let samples = [sample(0., 0.), sample(10., 5.), sample(20., -5.), sample(30., 0.), sample(40., 10.)];
+		let mut stabilizer = Stabilizer { resample: true, ..Stabilizer::default() };
+		let points = stroke(&mut stabilizer, &samples);
+
+		for s in samples {
+			assert!(points.iter().any(|p| p.distance(s) < 1e-4), "{s:?} missing");
+		}
+		// Close enough to draw a smooth curve
+		for pair in points.windows(2) {
+			assert!(pair[0].distance(pair[1]) <= SPACING * 1.5);
+		}
+	}
+}
@@ -0,0 +1,298 @@
+use std::fmt::Write;
+
+use crate::components::{BlendMode, LinePoint, Point, Size};
+use crate::components::layer::Layer;
+
+/// A stroke as drawn, with every point it was made of.
+#[derive(Clone)]
+pub struct LoggedStroke {
+	pub layer: u32,
+	pub points: Vec<LinePoint>,
+	pub color: [f32; 3],
+	pub opacity: f32,
+	pub blend_mode: BlendMode,
+	pub erase: bool,
+}
+
+#[derive(Clone)]
+pub enum LogEntry {
+	Stroke(LoggedStroke),
+	/// Everything drawn before on the layer is gone. Also recorded when a raster image replaces the layer.
+	Clear { layer: u32 },
+}
+
+impl LogEntry {
+	pub fn layer(&self) -> u32 {
+		match self {
+			LogEntry::Stroke(s) => s.layer,
+			LogEntry::Clear { layer } => *layer,
+		}
+	}
+}
+
+/// Vector record of what was drawn, following the undo history.
+#[derive(Default)]
+pub struct StrokeLog {
+	entries: Vec<LogEntry>,
+	/// Entries currently applied, the rest were undone
+	len: usize,
+	/// Entries waiting for the next history commit
+	pending: Vec<LogEntry>,
+}
+
+impl StrokeLog {
+	/// Applied entries, oldest first.
+	pub fn entries(&self) -> &[LogEntry] {
+		&self.entries[..self.len]
+	}
+
+	pub fn record(&mut self, entry: LogEntry) {
+		self.pending.push(entry);
+	}
+
+	/// Applies the pending entries, discarding undone ones. Returns the length before and after,
+	/// to be restored on undo and redo.
+	pub fn commit(&mut self) -> (usize, usize) {
+		let before = self.len;
+		self.entries.truncate(self.len);
+		self.entries.append(&mut self.pending);
+		self.len = self.entries.len();
+		(before, self.len)
+	}
+
+	pub fn set_len(&mut self, len: usize) {
+		self.len = len.min(self.entries.len());
+	}
+
+	/// Makes the applied entries permanent and moves them by `offset`, for when the canvas is resized.
+	pub fn rebase(&mut self, offset: Point) {
+		self.entries.truncate(self.len);
+		for entry in self.entries.iter_mut().chain(self.pending.iter_mut()) {
+			if let LogEntry::Stroke(s) = entry {
+				for p in s.points.iter_mut() {
+					p.pos += offset.into();
+				}
+			}
+		}
+	}
+
+	/// Replaces the log with already applied entries.
+	pub fn load(entries: Vec<LogEntry>) -> Self {
+		StrokeLog { len: entries.len(), entries, pending: vec![] }
+	}
+
+	/// SVG document with the visible layers, each stroke as paths with round caps and joins.
+	/// Erasing is expressed as masks over what was drawn before on the layer.
+	pub fn svg(&self, size: Size, background: [f32; 3], layers: &[Layer]) -> String {
+		let visible = layers.iter().filter(|l| l.visible).map(|l| (l.id, l.opacity, l.blend_mode));
+		self.svg_layers(size, background, visible)
+	}
+
+	/// `svg` for the layers given as id, opacity and blend mode, bottom to top.
+	fn svg_layers(&self, size: Size, background: [f32; 3], layers: impl Iterator<Item = (u32, f32, BlendMode)>) -> String {
+		let mut svg = String::new();
+		let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#, w = size.w, h = size.h);
+		let _ = writeln!(svg, r#"<rect width="{}" height="{}" fill="{}"/>"#, size.w, size.h, css_color(background));
+
+		let mut masks = 0;
+		for (id, opacity, blend_mode) in layers {
+			// Only what was drawn after the last clear is left
+			let entries: Vec<_> = self.entries().iter().filter(|e| e.layer() == id).collect();
+			let start = entries.iter().rposition(|e| matches!(e, LogEntry::Clear { .. })).map_or(0, |i| i + 1);
+
+			let mut content = String::new();
+			for entry in &entries[start..] {
+				let stroke = match entry {
+					LogEntry::Stroke(s) => s,
+					LogEntry::Clear { .. } => continue,
+				};
+
+				if stroke.erase {
+					masks += 1;
+					let _ = write!(
+						svg,
+						r#"<mask id="erase{masks}" maskUnits="userSpaceOnUse" x="0" y="0" width="{w}" height="{h}"><rect width="{w}" height="{h}" fill="white"/>{}</mask>"#,
+						stroke_group(stroke, [0., 0., 0.]),
+						w = size.w,
+						h = size.h,
+					);
+					let _ = writeln!(svg);
+					content = format!("<g mask=\"url(#erase{masks})\">\n{content}</g>\n");
+				} else {
+					let _ = writeln!(content, "{}", stroke_group(stroke, stroke.color));
+				}
+			}
+
+			let _ = writeln!(
+				svg,
+				r#"<g id="layer{}" opacity="{}"{}>"#,
+				id,
+				opacity,
+				blend_style(blend_mode),
+			);
+			svg += &content;
+			svg += "</g>\n";
+		}
+
+		svg += "</svg>\n";
+		svg
+	}
+}
+
+fn css_color(color: [f32; 3]) -> String {
+	let [r, g, b] = color.map(|c| (c.clamp(0., 1.) * 255.).round() as u8);
+	format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+fn blend_style(mode: BlendMode) -> &'static str {
+	match mode {
+		BlendMode::Normal => "",
+		BlendMode::Multiply => r#" style="mix-blend-mode:multiply""#,
+		BlendMode::Screen => r#" style="mix-blend-mode:screen""#,
+		BlendMode::Overlay => r#" style="mix-blend-mode:overlay""#,
+		BlendMode::Darken => r#" style="mix-blend-mode:darken""#,
+		BlendMode::Lighten => r#" style="mix-blend-mode:lighten""#,
+	}
+}
+
+/// The stroke opacity applies to the group so overlapping paths are composited once, like on the canvas.
+/// Paths are split where the radius or opacity of the points changes.
+fn stroke_group(stroke: &LoggedStroke, color: [f32; 3]) -> String {
+	let mut group = format!(
+		r#"<g opacity="{}"{} fill="none" stroke="{}" stroke-linecap="round" stroke-linejoin="round">"#,
+		stroke.opacity,
+		if stroke.erase { "" } else { blend_style(stroke.blend_mode) },
+		css_color(color),
+	);
+
+	let coords = |p: &LinePoint| (p.pos.x, p.pos.y);
+
+	let mut run_start = 0;
+	for i in 1..=stroke.points.len() {
+		let start = &stroke.points[run_start];
+		if i < stroke.points.len() && stroke.points[i].radius == start.radius && stroke.points[i].opacity == start.opacity {
+			continue;
+		}
+
+		// Runs share their boundary point so the path stays connected
+		let run = &stroke.points[run_start.saturating_sub(1)..i];
+		let (x, y) = coords(&run[0]);
+		let mut d = format!("M{x} {y}");
+		if run.len() == 1 {
+			// A dot, drawn by the round caps
+			d += &format!(" L{x} {y}");
+		}
+		for p in &run[1..] {
+			let (x, y) = coords(p);
+			let _ = write!(d, " L{x} {y}");
+		}
+		let _ = write!(group, r#"<path d="{d}" stroke-width="{}" stroke-opacity="{}"/>"#, 2. * start.radius, start.opacity);
+
+		run_start = i;
+	}
+
+	group += "</g>";
+	group
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::components::PointF;
+
+	const SIZE: Size = Size { w: 8, h: 8 };
+	const LAYERS: [(u32, f32, BlendMode); 1] = [(0, 1., BlendMode::Normal)];
+
+	fn stroke(x: f32, color: [f32; 3], erase: bool) -> LogEntry {
+		let point = |y| LinePoint { pos: PointF { x, y }, radius: 1., opacity: 1. };
+		LogEntry::Stroke(LoggedStroke {
+			layer: 0,
+			points: vec![point(1.), point(5.)],
+			color,
+			opacity: 1.,
+			blend_mode: BlendMode::Normal,
+			erase,
+		})
+	}
+
+	fn xs(log: &StrokeLog) -> Vec<f32> {
+		log.entries()
+			.iter()
+			.map(|e| match e {
+				LogEntry::Stroke(s) => s.points[0].pos.x,
+				LogEntry::Clear { .. } => -1.,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn erased_stroke_masks_what_was_drawn_before() {
+		let mut log = StrokeLog::default();
+		log.record(stroke(1., [1., 0., 0.], false));
+		log.record(stroke(1., [0., 1., 0.], true));
+		log.record(stroke(3., [0., 0., 1.], false));
+		log.commit();
+
+		let svg = log.svg_layers(SIZE, [1., 1., 1.], LAYERS.into_iter());
+		// The eraser is drawn black in a mask, its own color is left out
+		assert!(svg.contains(r#"<mask id="erase1""#));
+		assert!(!svg.contains("#00ff00"));
+		let masked = svg.find(r#"<g mask="url(#erase1)">"#).unwrap();
+		let red = svg.find("#ff0000").unwrap();
+		let blue = svg.find("#0000ff").unwrap();
+		let end = masked + svg[masked..].find("</g>\n</g>").unwrap();
+		assert!(masked < red && red < end, "The stroke before the eraser isn't masked");
+		assert!(blue > end, "The stroke after the eraser is masked");
+	}
+
+	#[test]
+	fn undone_entries_are_left_out_and_discarded_on_commit() {
+		let mut log = StrokeLog::default();
+		log.record(stroke(1., [1., 0., 0.], false));
+		assert_eq!(log.commit(), (0, 1));
+		log.record(stroke(2., [0., 0., 1.], false));
+		assert_eq!(log.commit(), (1, 2));
+
+		log.set_len(1);
+		assert_eq!(xs(&log), [1.]);
+		let svg = log.svg_layers(SIZE, [1., 1., 1.], LAYERS.into_iter());
+		assert!(svg.contains("#ff0000"));
+		assert!(!svg.contains("#0000ff"));
+
+		// Redo restores it until something else is drawn
+		log.set_len(2);
+		assert_eq!(xs(&log), [1., 2.]);
+		log.set_len(1);
+		log.record(stroke(3., [0., 1., 0.], false));
+		assert_eq!(log.commit(), (1, 2));
+		assert_eq!(xs(&log), [1., 3.]);
+		log.set_len(5);
+		assert_eq!(xs(&log), [1., 3.]);
+	}
+
+	#[test]
+	fn rebase_moves_strokes_and_drops_undone_ones() {
+		let mut log = StrokeLog::default();
+		log.record(stroke(1., [1., 0., 0.], false));
+		log.record(LogEntry::Clear { layer: 0 });
+		log.record(stroke(2., [1., 0., 0.], false));
+		log.commit();
+		log.record(stroke(3., [1., 0., 0.], false));
+		log.commit();
+		log.set_len(3);
+		// Waiting for the next commit when the canvas is resized
+		log.record(stroke(4., [1., 0., 0.], false));
+
+		log.rebase(Point { x: 2, y: -1 });
+		assert_eq!(log.commit(), (3, 4));
+		assert_eq!(xs(&log), [3., -1., 4., 6.]);
+		for entry in log.entries() {
+			if let LogEntry::Stroke(s) = entry {
+				assert_eq!((s.points[0].pos.y, s.points[1].pos.y), (0., 4.));
+			}
+		}
+		// The undone stroke is gone, redoing can't bring it back
+		log.set_len(5);
+		assert_eq!(log.entries().len(), 4);
+	}
+}
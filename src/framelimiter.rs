@@ -4,7 +4,7 @@ use winit::{
 };
 use core::cmp::Reverse;
 use std::{
-	time::{SystemTime, Duration}, collections::{BinaryHeap, HashMap}, sync::mpsc, thread,
+	time::{SystemTime, Duration, Instant}, collections::{BinaryHeap, HashMap}, sync::mpsc, thread,
 };
 
 use crate::CustomEvents;
@@ -126,3 +126,66 @@ impl FrameLimiter {
 		self.sender.send(wid).unwrap();
 	}
 }
+
+/// When animated windows should be redrawn next. Deadlines are kept between passes of the event loop,
+/// a window is redrawn on the first pass after its deadline is reached.
+#[derive(Default)]
+pub struct AnimationTimer {
+	deadlines: HashMap<WindowId, Instant>,
+}
+
+impl AnimationTimer {
+	/// Replaces the deadline of `wid` with `next`, `None` if it stopped animating.
+	/// Returns whether the previous one was reached by `now`, so a frame is due.
+	pub fn update(&mut self, wid: WindowId, next: Option<Instant>, now: Instant) -> bool {
+		let due = self.deadlines.get(&wid).is_some_and(|time| *time <= now);
+		match next {
+			Some(time) => self.deadlines.insert(wid, time),
+			None => self.deadlines.remove(&wid),
+		};
+		due
+	}
+
+	/// Earliest deadline, the event loop should wake up then.
+	pub fn next_wake(&self) -> Option<Instant> {
+		self.deadlines.values().min().copied()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn animation_frame_is_due_once_the_deadline_passes() {
+		let wid = unsafe { WindowId::dummy() };
+		let start = Instant::now();
+		let step = Duration::from_millis(125);
+		let mut timer = AnimationTimer::default();
+
+		// Like the ants, the next deadline is always the next step after now
+		let next = |now: Instant| Some(start + step * ((now - start).as_millis() / step.as_millis() + 1) as u32);
+
+		assert!(!timer.update(wid, next(start), start));
+		assert_eq!(timer.next_wake(), Some(start + step));
+
+		// Woken up early by other events
+		let early = start + step / 2;
+		assert!(!timer.update(wid, next(early), early));
+		assert_eq!(timer.next_wake(), Some(start + step));
+
+		// Woken up by the deadline
+		let woken = start + step;
+		assert!(timer.update(wid, next(woken), woken));
+		assert_eq!(timer.next_wake(), Some(start + step * 2));
+
+		// Late, after several steps
+		let late = start + step * 5;
+		assert!(timer.update(wid, next(late), late));
+		assert_eq!(timer.next_wake(), Some(start + step * 6));
+
+		// Stopped animating
+		assert!(!timer.update(wid, None, late + step / 2));
+		assert_eq!(timer.next_wake(), None);
+	}
+}
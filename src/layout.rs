@@ -3,6 +3,59 @@ use async_trait::async_trait;
 use std::sync::Arc;
 use winit::{event::WindowEvent, event_loop::EventLoopWindowTarget, window::Window};
 
+/// Brush colors selectable with the number keys 1 to 8.
+const PALETTE: [[f32; 3]; 8] = [
+	[1., 1., 1.],
+	[0., 0., 0.],
+	[1., 0., 0.],
+	[0., 1., 0.],
+	[0., 0., 1.],
+	[1., 1., 0.],
+	[0., 1., 1.],
+	[1., 0., 1.],
+];
+
+/// Color shown around the canvas.
+const WINDOW_COLOR: wgpu::Color = wgpu::Color { r: 0.1, g: 0.1, b: 0.1, a: 1. };
+
+/// Scroll distance in pixels equivalent to one wheel notch.
+const PIXELS_PER_SCROLL_LINE: f32 = 40.;
+
+/// Change of the fill tolerance per key press.
+const FILL_TOLERANCE_STEP: u8 = 8;
+
+/// File name in the working directory for an export, made unique by the current time.
+fn timestamped_path(extension: &str) -> String {
+	let secs = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap()
+		.as_secs();
+	format!("pntr-{secs}.{extension}")
+}
+
+/// Normalized pressure of a touch, full pressure for devices that don't report it.
+fn touch_pressure(force: Option<winit::event::Force>) -> f32 {
+	force.map_or(1., |f| f.normalized() as f32)
+}
+
+/// What started the current stroke, other buttons and touches are ignored until it's released.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum StrokeInput {
+	Mouse(winit::event::MouseButton),
+	/// Id of the touch, the same for all of its events
+	Touch(u64),
+}
+
+/// Whether a touch event is handled: it starts a stroke only if none is in progress,
+/// and only the touch that started one moves or ends it.
+fn handles_touch(held: Option<StrokeInput>, id: u64, phase: winit::event::TouchPhase) -> bool {
+	match (held, phase) {
+		(None, winit::event::TouchPhase::Started) => true,
+		(Some(StrokeInput::Touch(held)), _) => held == id,
+		_ => false,
+	}
+}
+
 pub enum WindowLifeStatus {
 	Alive,
 	Dead,
@@ -11,6 +64,8 @@ pub enum WindowLifeStatus {
 #[derive(Default)]
 pub struct LayoutContext {
 	wgpu: Option<wgpu::Instance>,
+	/// Image to open on startup
+	pub file: Option<std::path::PathBuf>,
 }
 
 #[allow(unused)]
@@ -65,18 +120,24 @@ pub trait Layout {
 
 	/// Returns a life status and maybe another layout, notice that if the child layout uses the same window, the parent layout must pronounce itself as dead.
 	fn event_handler(&mut self, _: winit::event::WindowEvent, _: &FrameLimiter);
+
+	/// When the layout changes next without input, so it should be redrawn then. `None` if it doesn't.
+	fn next_animation_frame(&self) -> Option<std::time::Instant> {
+		None
+	}
 }
 
 pub struct DrawingWindow {
 	window: Arc<Window>,
 	surface: wgpu::Surface,
-	queue: wgpu::Queue,
 	config: wgpu::SurfaceConfiguration,
 	size: winit::dpi::PhysicalSize<u32>,
 
 	ctx: components::Context,
 
 	canvas: Box<components::Canvas>,
+	modifiers: winit::event::ModifiersState,
+	stroke_input: Option<StrokeInput>,
 
 	//Events:
 	resized: bool,
@@ -137,19 +198,27 @@ impl Layout for DrawingWindow {
 
 		surface.configure(&device, &config);
 
-		let mut ctx = components::Context::new(device, config.format);
+		let mut ctx = components::Context::new(device, queue, config.format);
 
-		let canvas = components::Canvas::new(&mut ctx);
+		let canvas_size = components::Size { w: size.width.max(1), h: size.height.max(1) };
+		let mut canvas = components::Canvas::with_size(&mut ctx, canvas_size);
+
+		if let Some(path) = layout_ctx.file {
+			if let Err(e) = canvas.open(&ctx, &path) {
+				eprintln!("Could not open {}: {e}", path.display());
+			}
+		}
 
 		return Box::new(Self {
 			window,
 			surface,
-			queue,
 			config,
 			size,
 
 			ctx,
 			canvas,
+			modifiers: winit::event::ModifiersState::empty(),
+			stroke_input: None,
 
 			resized: false,
 			close: false,
@@ -177,6 +246,19 @@ impl Layout for DrawingWindow {
 							label: Some("Render Encoder"),
 						});
 
+				encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+					label: Some("Clear Pass"),
+					color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+						view: &view,
+						resolve_target: None,
+						ops: wgpu::Operations {
+							load: wgpu::LoadOp::Clear(WINDOW_COLOR),
+							store: true,
+						},
+					})],
+					depth_stencil_attachment: None,
+				});
+
 				self.canvas.render(
 					&mut encoder,
 					&mut self.ctx,
@@ -186,7 +268,7 @@ impl Layout for DrawingWindow {
 				);
 
 				self.ctx.staging_belt.finish();
-				self.queue.submit(std::iter::once(encoder.finish()));
+				self.ctx.queue.submit(std::iter::once(encoder.finish()));
 				self.ctx.staging_belt.recall();
 				output.present();
 			}
@@ -210,6 +292,16 @@ impl Layout for DrawingWindow {
 			self.config.width = new_size.width;
 			self.config.height = new_size.height;
 			self.surface.configure(&self.ctx.device, &self.config);
+
+			// Grow the canvas so the whole window can be drawn on
+			let canvas_size = self.canvas.size();
+			if new_size.width > canvas_size.w || new_size.height > canvas_size.h {
+				let size = components::Size {
+					w: std::cmp::max(new_size.width, canvas_size.w),
+					h: std::cmp::max(new_size.height, canvas_size.h),
+				};
+				self.canvas.resize(&self.ctx, size, components::Anchor::TOP_LEFT);
+			}
 		}
 
 		if self.close {
@@ -220,6 +312,10 @@ impl Layout for DrawingWindow {
 		(Alive, None)
 	}
 
+	fn next_animation_frame(&self) -> Option<std::time::Instant> {
+		self.canvas.next_animation_frame()
+	}
+
 	fn event_handler(&mut self, event: winit::event::WindowEvent, frame_limiter: &FrameLimiter) {
 		use WindowEvent::*;
 
@@ -230,6 +326,15 @@ impl Layout for DrawingWindow {
 				self.resized = true;
 			}
 
+			ModifiersChanged(modifiers) => {
+				self.modifiers = modifiers;
+				self.canvas.set_constrain(modifiers.shift());
+				self.canvas.set_duplicate(modifiers.ctrl());
+				if self.canvas.needs_redraw() {
+					frame_limiter.schedule_redraw(self.window().id());
+				}
+			}
+
 			KeyboardInput {
 				input:
 					winit::event::KeyboardInput {
@@ -239,9 +344,145 @@ impl Layout for DrawingWindow {
 					},
 				..
 			} => {
+				use winit::event::VirtualKeyCode as Key;
 				let mut redraw = true;
 				match letter {
-					winit::event::VirtualKeyCode::C => self.canvas.clear(),
+					Key::C => self.canvas.clear(&self.ctx),
+					Key::E if self.modifiers.ctrl() && self.modifiers.shift() => {
+						redraw = false;
+						let path = timestamped_path("svg");
+						match self.canvas.export_svg(&path) {
+							Ok(()) => println!("Strokes exported to {path}"),
+							Err(e) => eprintln!("Could not export strokes to {path}: {e}"),
+						}
+					}
+					Key::E if self.modifiers.ctrl() => {
+						redraw = false;
+						let path = timestamped_path(components::ORA_EXTENSION);
+						match self.canvas.export_ora(&self.ctx, &path) {
+							Ok(()) => println!("Layers exported to {path}"),
+							Err(e) => eprintln!("Could not export layers to {path}: {e}"),
+						}
+					}
+					Key::S if self.modifiers.ctrl() && self.modifiers.shift() => {
+						redraw = false;
+						let path = timestamped_path(components::PROJECT_EXTENSION);
+						match self.canvas.save_project(&self.ctx, &path) {
+							Ok(()) => println!("Project saved to {path}"),
+							Err(e) => eprintln!("Could not save project to {path}: {e}"),
+						}
+					}
+					Key::S if self.modifiers.ctrl() => {
+						redraw = false;
+						let path = timestamped_path("png");
+						match self.canvas.export_png(&self.ctx, &path) {
+							Ok(()) => println!("Canvas exported to {path}"),
+							Err(e) => eprintln!("Could not export canvas to {path}: {e}"),
+						}
+					}
+					Key::F if self.modifiers.shift() => self.canvas.fill(&self.ctx, components::FillMode::Global),
+					Key::F => self.canvas.fill(&self.ctx, components::FillMode::Contiguous),
+					Key::Minus => {
+						redraw = false;
+						self.canvas.set_fill_tolerance(self.canvas.fill_tolerance().saturating_sub(FILL_TOLERANCE_STEP));
+					}
+					Key::Equals => {
+						redraw = false;
+						self.canvas.set_fill_tolerance(self.canvas.fill_tolerance().saturating_add(FILL_TOLERANCE_STEP));
+					}
+					Key::T => {
+						self.canvas.cycle_shape_tool();
+						match self.canvas.shape_tool() {
+							Some(kind) => println!("Tool: {kind:?}"),
+							None => println!("Tool: Freehand"),
+						}
+					}
+					Key::O => self.canvas.toggle_shape_fill(),
+					Key::Return => self.canvas.finish_shape(),
+					Key::Escape if self.canvas.selection_tool().is_some() => self.canvas.deselect(),
+					Key::Escape => self.canvas.cancel_shape(),
+					Key::M => {
+						self.canvas.cycle_selection_tool();
+						match self.canvas.selection_tool() {
+							Some(kind) => println!("Selection: {kind:?}"),
+							None => println!("Selection: Off"),
+						}
+					}
+					Key::D if self.modifiers.ctrl() => self.canvas.deselect(),
+					Key::N => self.canvas.add_layer(&self.ctx),
+					Key::Delete if self.canvas.has_selection() => self.canvas.delete_selection(&self.ctx),
+					Key::Delete => self.canvas.delete_layer(),
+					Key::H => self.canvas.toggle_layer_visibility(),
+					Key::PageUp if self.modifiers.ctrl() => self.canvas.move_layer(1),
+					Key::PageDown if self.modifiers.ctrl() => self.canvas.move_layer(-1),
+					Key::PageUp => {
+						redraw = false;
+						self.canvas.select_layer(self.canvas.active_layer() + 1);
+						println!("Active layer: {}", self.canvas.layers()[self.canvas.active_layer()].name);
+					}
+					Key::PageDown => {
+						redraw = false;
+						self.canvas.select_layer(self.canvas.active_layer().saturating_sub(1));
+						println!("Active layer: {}", self.canvas.layers()[self.canvas.active_layer()].name);
+					}
+					Key::B if self.modifiers.ctrl() => {
+						let layer = &self.canvas.layers()[self.canvas.active_layer()];
+						self.canvas.set_layer_blend_mode(layer.blend_mode.next());
+					}
+					Key::Comma if self.modifiers.ctrl() => {
+						let layer = &self.canvas.layers()[self.canvas.active_layer()];
+						self.canvas.set_layer_opacity(layer.opacity - 0.1);
+					}
+					Key::Period if self.modifiers.ctrl() => {
+						let layer = &self.canvas.layers()[self.canvas.active_layer()];
+						self.canvas.set_layer_opacity(layer.opacity + 0.1);
+					}
+					Key::S => {
+						redraw = false;
+						self.canvas.cycle_stabilizer();
+					}
+					Key::R => {
+						redraw = false;
+						self.canvas.toggle_stroke_resampling();
+					}
+					Key::A => {
+						redraw = false;
+						self.canvas.toggle_antialias();
+					}
+					Key::B => {
+						redraw = false;
+						self.canvas.set_blend_mode(self.canvas.blend_mode().next());
+					}
+					Key::L => {
+						redraw = false;
+						self.canvas.toggle_linear_blending();
+					}
+					Key::E => {
+						redraw = false;
+						self.canvas.toggle_eraser();
+					}
+					Key::Z if self.modifiers.ctrl() && self.modifiers.shift() => self.canvas.redo(),
+					Key::Z if self.modifiers.ctrl() => self.canvas.undo(),
+					Key::LBracket => {
+						redraw = false;
+						self.canvas.set_brush_radius(self.canvas.brush_radius() - 1.);
+					}
+					Key::RBracket => {
+						redraw = false;
+						self.canvas.set_brush_radius(self.canvas.brush_radius() + 1.);
+					}
+					Key::Comma => {
+						redraw = false;
+						self.canvas.set_brush_opacity(self.canvas.brush_opacity() - 0.1);
+					}
+					Key::Period => {
+						redraw = false;
+						self.canvas.set_brush_opacity(self.canvas.brush_opacity() + 0.1);
+					}
+					Key::Key1 | Key::Key2 | Key::Key3 | Key::Key4 | Key::Key5 | Key::Key6 | Key::Key7 | Key::Key8 => {
+						redraw = false;
+						self.canvas.set_brush_color(PALETTE[letter as usize - Key::Key1 as usize]);
+					}
 					_ => redraw = false,
 				}
 				if redraw {
@@ -251,24 +492,135 @@ impl Layout for DrawingWindow {
 
 			MouseInput {
 				state,
-				button: winit::event::MouseButton::Left,
+				button: button @ (winit::event::MouseButton::Left | winit::event::MouseButton::Right),
 				..
 			} => {
-				use winit::event::ElementState;
-				match state {
-					ElementState::Pressed => self.canvas.mouse_down(),
-					ElementState::Released => self.canvas.mouse_up(),
+				use winit::event::{ElementState, MouseButton};
+				match (state, self.stroke_input) {
+					(ElementState::Pressed, None) => {
+						self.stroke_input = Some(StrokeInput::Mouse(button));
+						match button {
+							MouseButton::Right => self.canvas.erase_down(),
+							_ => self.canvas.mouse_down(),
+						}
+					}
+					(ElementState::Released, Some(StrokeInput::Mouse(held))) if held == button => {
+						self.stroke_input = None;
+						self.canvas.mouse_up();
+					}
+					_ => return,
+				}
+				frame_limiter.schedule_redraw(self.window().id());
+			}
+
+			MouseInput {
+				state,
+				button: winit::event::MouseButton::Middle,
+				..
+			} => {
+				self.canvas.set_panning(state == winit::event::ElementState::Pressed);
+			}
+
+			MouseWheel { delta, .. } => {
+				use winit::event::MouseScrollDelta;
+				let steps = match delta {
+					MouseScrollDelta::LineDelta(_, y) => y,
+					MouseScrollDelta::PixelDelta(p) => p.y as f32 / PIXELS_PER_SCROLL_LINE,
+				};
+				self.canvas.zoom(steps);
+				frame_limiter.schedule_redraw(self.window().id());
+			}
+
+			DroppedFile(path) => {
+				match self.canvas.open(&self.ctx, &path) {
+					Ok(()) => frame_limiter.schedule_redraw(self.window().id()),
+					Err(e) => eprintln!("Could not open {}: {e}", path.display()),
+				}
+			}
+
+			Touch(winit::event::Touch { phase, location, force, id, .. }) => {
+				use winit::event::TouchPhase;
+				if !handles_touch(self.stroke_input, id, phase) {
+					return;
+				}
+				self.canvas.pen_pos(location.into(), touch_pressure(force));
+				match phase {
+					TouchPhase::Started => {
+						self.stroke_input = Some(StrokeInput::Touch(id));
+						self.canvas.mouse_down();
+					}
+					TouchPhase::Ended | TouchPhase::Cancelled => {
+						self.stroke_input = None;
+						self.canvas.mouse_up();
+					}
+					TouchPhase::Moved => (),
 				}
 				frame_limiter.schedule_redraw(self.window().id());
 			}
 
 			CursorMoved { position, .. } => {
-				// TODO: Don't redraw window if no line was drawn
+				// Only moves that draw or pan change what's shown
 				self.canvas.mouse_pos(position.into());
-				frame_limiter.schedule_redraw(self.window().id());
+				if self.canvas.needs_redraw() {
+					frame_limiter.schedule_redraw(self.window().id());
+				}
 			}
 
 			_ => (),
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use winit::event::Force;
+
+	#[test]
+	fn touch_force_maps_to_brush() {
+		let curve = components::PressureCurve::default();
+		let forces = [0., 0.25, 0.5, 0.75, 1., 1.5];
+		let mapped: Vec<(f32, f32)> = forces
+			.iter()
+			.map(|&force| Force::Calibrated { force, max_possible_force: 1., altitude_angle: None })
+			.map(|f| curve.map(touch_pressure(Some(f))))
+			.collect();
+
+		assert_eq!(mapped[0], (curve.min_radius, curve.min_opacity));
+		assert!(mapped.windows(2).all(|w| w[0].0 <= w[1].0 && w[0].1 <= w[1].1));
+		// Forces past the maximum the device reports are clamped
+		assert_eq!(mapped[4], (1., 1.));
+		assert_eq!(mapped[5], (1., 1.));
+	}
+
+	#[test]
+	fn touch_without_force_is_full_pressure() {
+		assert_eq!(touch_pressure(None), 1.);
+		let normalized = touch_pressure(Some(Force::Normalized(0.5)));
+		assert_eq!(normalized, 0.5);
+	}
+
+	#[test]
+	fn only_the_stroke_touch_is_handled() {
+		use winit::event::{MouseButton, TouchPhase};
+
+		assert!(handles_touch(None, 1, TouchPhase::Started));
+		assert!(!handles_touch(None, 1, TouchPhase::Moved));
+		assert!(!handles_touch(None, 1, TouchPhase::Ended));
+
+		let held = Some(StrokeInput::Touch(1));
+		assert!(handles_touch(held, 1, TouchPhase::Moved));
+		assert!(handles_touch(held, 1, TouchPhase::Ended));
+		assert!(handles_touch(held, 1, TouchPhase::Cancelled));
+		// A second finger neither moves nor ends the stroke
+		for phase in [TouchPhase::Started, TouchPhase::Moved, TouchPhase::Ended, TouchPhase::Cancelled] {
+			assert!(!handles_touch(held, 2, phase));
+		}
+
+		// Nor does a touch while a mouse button draws
+		let held = Some(StrokeInput::Mouse(MouseButton::Left));
+		for phase in [TouchPhase::Started, TouchPhase::Moved, TouchPhase::Ended, TouchPhase::Cancelled] {
+			assert!(!handles_touch(held, 1, phase));
+		}
+	}
+}
@@ -7,13 +7,13 @@ use winit::{
 use std::{
 	collections::HashMap,
 	sync::Arc,
-	time::Duration,
+	time::{Duration, Instant},
 };
 
 mod components;
 mod layout;
 mod framelimiter;
-use framelimiter::FrameLimiter;
+use framelimiter::{AnimationTimer, FrameLimiter};
 use layout::Layout;
 use layout::WindowLifeStatus;
 
@@ -36,7 +36,8 @@ async fn run() {
 	let frame_limiter = FrameLimiter::new(&event_loop);
 
 	// Start initial layout
-	let ctx = InitialLayout::init();
+	let mut ctx = InitialLayout::init();
+	ctx.file = std::env::args_os().nth(1).map(Into::into);
 
 	let window = Arc::new(Window::new(&event_loop).expect("Could not create window"));
 
@@ -45,8 +46,14 @@ async fn run() {
 
 	window_map.insert(initial_layout.window().id(), initial_layout);
 
+	let mut animations = AnimationTimer::default();
+
 	event_loop.run(move |event, event_loop, control_flow| {
-		control_flow.set_wait();
+		// The loop sleeps until the next frame of an animation is due
+		match animations.next_wake() {
+			Some(time) => control_flow.set_wait_until(time),
+			None => control_flow.set_wait(),
+		}
 
 		match event {
 			Event::WindowEvent { window_id, event } => {
@@ -62,6 +69,7 @@ async fn run() {
 			Event::MainEventsCleared => {
 				let mut should_remove: Vec<WindowId> = Vec::new();
 				let mut should_add: Vec<Box<dyn Layout>> = Vec::new();
+				let now = Instant::now();
 				window_map.values_mut().for_each(|layout| {
 					let (window_state, child) = layout.update(event_loop);
 
@@ -72,9 +80,15 @@ async fn run() {
 					if let Some(child_layout) = child {
 						should_add.push(child_layout);
 					}
+
+					let wid = layout.window().id();
+					if animations.update(wid, layout.next_animation_frame(), now) {
+						frame_limiter.schedule_redraw(wid);
+					}
 				});
 
 				for win_id in should_remove {
+					animations.update(win_id, None, now);
 					window_map.remove(&win_id);
 				}
 
@@ -89,6 +103,8 @@ async fn run() {
 
 				if window_map.is_empty() {
 					control_flow.set_exit_with_code(0);
+				} else if let Some(time) = animations.next_wake() {
+					control_flow.set_wait_until(time);
 				}
 			}
 